@@ -0,0 +1,111 @@
+use regex::Regex;
+use semver::{Version, VersionReq};
+use anyhow::{Result, anyhow, bail};
+
+fn number(s: &str) -> Result<f64> {
+  s.trim().parse().map_err(|_| anyhow!("cannot compare numerically, value is not numeric: '{}'", s))
+}
+
+/// Reads a version leniently: `v1.2` is understood as `1.2.0`
+fn version(s: &str) -> Result<Version> {
+  let s = s.trim();
+  let s = s.strip_prefix('v').unwrap_or(s);
+  let core_len = s.find(['-', '+']).unwrap_or(s.len());
+  let missing = 2usize.saturating_sub(s[..core_len].matches('.').count());
+  let padded = format!("{}{}{}", &s[..core_len], ".0".repeat(missing), &s[core_len..]);
+  Version::parse(&padded).map_err(|_| anyhow!("cannot compare versions, value is not a version: '{}'", s))
+}
+
+/// Reads a list given either as a JSON list or as comma-separated values
+fn list(s: &str) -> Vec<String> {
+  crate::text::parse_list(s)
+    .unwrap_or_else(|_| s.split(',').map(|item| item.trim().to_string()).collect())
+}
+
+/// Reads bounds given as `min,max` or `min..max`
+fn bounds(s: &str) -> Result<(f64, f64)> {
+  let (min, max) = s.split_once("..").or(s.split_once(','))
+    .ok_or(anyhow!("between needs bounds as min,max or min..max, got '{}'", s))?;
+  Ok((number(min)?, number(max)?))
+}
+
+/// Compares a value with a reference according to a comparison type (`compare_for`):
+/// - text: equality, not_equal, contains, starts_with, ends_with, matches (regex), is_empty, in (list)
+/// - numbers: gt, gte, lt, lte, between (inclusive bounds)
+/// - versions: version_eq, version_gt, version_gte, version_lt, version_lte, version_matches (requirement such as `>=1.2, <2`)
+pub fn compare(value: &str, compare_for: &str, compare_with: &str) -> Result<bool> {
+  Ok(match compare_for {
+    "equality" => value == compare_with,
+    "not_equal" => value != compare_with,
+    "contains" => value.contains(compare_with),
+    "starts_with" => value.starts_with(compare_with),
+    "ends_with" => value.ends_with(compare_with),
+    "matches" => Regex::new(compare_with).map_err(|_| anyhow!("error creating regex from input: {}", compare_with))?.is_match(value),
+    "is_empty" => value.trim().is_empty(),
+    "in" => list(compare_with).iter().any(|item| item == value),
+    "gt" => number(value)? > number(compare_with)?,
+    "gte" => number(value)? >= number(compare_with)?,
+    "lt" => number(value)? < number(compare_with)?,
+    "lte" => number(value)? <= number(compare_with)?,
+    "between" => {
+      let (min, max) = bounds(compare_with)?;
+      (min..=max).contains(&number(value)?)
+    },
+    "version_eq" => version(value)? == version(compare_with)?,
+    "version_gt" => version(value)? > version(compare_with)?,
+    "version_gte" => version(value)? >= version(compare_with)?,
+    "version_lt" => version(value)? < version(compare_with)?,
+    "version_lte" => version(value)? <= version(compare_with)?,
+    "version_matches" => VersionReq::parse(compare_with)
+      .map_err(|_| anyhow!("invalid version requirement: {}", compare_with))?
+      .matches(&version(value)?),
+    _ => bail!("unsupported comparison type: {}", compare_for)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compare() {
+    assert!(compare("up", "equality", "up").unwrap());
+    assert!(compare("up", "not_equal", "down").unwrap());
+    assert!(compare("10", "gt", "9.5").unwrap());
+    assert!(!compare(" 10 ", "lt", "9.5").unwrap());
+    assert!(compare("disk full", "contains", "full").unwrap());
+    assert!(compare("error 503", "matches", r"^error 5\d\d$").unwrap());
+    assert!(compare("n/a", "gt", "1").is_err());
+    assert!(compare("a", "sounds_like", "b").is_err());
+  }
+
+  #[test]
+  fn test_compare_text_and_lists() {
+    assert!(compare("warning: disk", "starts_with", "warning").unwrap());
+    assert!(compare("backup.tar.gz", "ends_with", ".gz").unwrap());
+    assert!(compare(" \n", "is_empty", "").unwrap());
+    assert!(!compare("x", "is_empty", "").unwrap());
+    assert!(compare("b", "in", "a, b, c").unwrap());
+    assert!(compare("b", "in", r#"["a","b"]"#).unwrap());
+    assert!(!compare("d", "in", "a,b,c").unwrap());
+  }
+
+  #[test]
+  fn test_compare_ranges() {
+    assert!(compare("15", "between", "10,20").unwrap());
+    assert!(compare("20", "between", "10..20").unwrap());
+    assert!(!compare("20.5", "between", "10..20").unwrap());
+    assert!(compare("-5", "between", "-10..0").unwrap());
+    assert!(compare("15", "between", "10").is_err());
+  }
+
+  #[test]
+  fn test_compare_versions() {
+    assert!(compare("1.10.0", "version_gt", "1.9.3").unwrap());
+    assert!(compare("v2.1", "version_gte", "2.1.0").unwrap());
+    assert!(compare("2.0.0-rc1", "version_lt", "2.0.0").unwrap());
+    assert!(compare("1.4.2", "version_matches", ">=1.2, <2").unwrap());
+    assert!(compare("3", "version_eq", "3.0.0").unwrap());
+    assert!(compare("latest", "version_gt", "1.0").is_err());
+  }
+}
@@ -1,60 +1,118 @@
-use std::collections::HashMap ;
+use std::{collections::HashMap, fs, io::BufReader, sync::Arc, time::Instant};
 
 use serde_json::Value;
-use reqwest::{self, StatusCode};
-use anyhow::{Result, anyhow, bail};
-
-fn handle_retry<T,F>(retries: u8, retry_secs: f64, closure: F ) 
--> Result<T>
-where F: Fn() -> Result<T> {
-  match (closure(), retries) {
-    (Ok(r),_) => Ok(r),
-    (Err(s),0) => Err(s),
-    (Err(_),_) => {
-      log::debug!("Error happened but {} {} left, will retry in {} seconds",
-        retries,
-        if retries == 1 {"retry"} else { "retries" },
-        retry_secs);
-      std::thread::sleep(std::time::Duration::from_secs_f64(retry_secs));
-      handle_retry(retries - 1, retry_secs, closure)
-    } 
+use reqwest::{self, StatusCode, blocking::RequestBuilder};
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use anyhow::{Error, Result, anyhow};
+
+use crate::{retry::{self, ErrorKind}, yamlworkflow::RetryPolicy};
+
+/// An HTTP client shared by all steps of a flow, so that cookies set by one request are sent with the next ones.
+/// The cookie jar can optionally be persisted to a file, to keep sessions alive between runs.
+#[derive(Clone)]
+pub struct HttpSession {
+  client: reqwest::blocking::Client,
+  cookies: Arc<CookieStoreMutex>,
+  cookie_file: Option<String>,
+}
+
+impl HttpSession {
+  pub fn new(cookie_file: Option<String>) -> Result<Self> {
+    let store = match &cookie_file {
+      Some(path) => match fs::File::open(path) {
+        Ok(file) => CookieStore::load_json(BufReader::new(file))
+          .map_err(|e| anyhow!("could not load cookies from {}: {}", path, e))?,
+        Err(_) => {
+          log::debug!("cookie file {} not found, starting with an empty cookie jar", path);
+          CookieStore::default()
+        }
+      },
+      None => CookieStore::default()
+    };
+    let cookies = Arc::new(CookieStoreMutex::new(store));
+    let client = reqwest::blocking::Client::builder()
+      .cookie_provider(cookies.clone())
+      .build()
+      .map_err(|_| anyhow!("could not create HTTP client"))?;
+    Ok(Self { client, cookies, cookie_file })
   }
+
+  /// Writes the cookie jar to its file, if one was configured. Session cookies are kept too, as this is
+  /// typically what a form login hands out.
+  pub fn save_cookies(&self) -> Result<()> {
+    if let Some(path) = &self.cookie_file {
+      let mut file = create_private(path).map_err(|_| anyhow!("could not open cookie file {} for writing", path))?;
+      self.cookies.lock().map_err(|_| anyhow!("cookie jar lock poisoned"))?
+        .save_incl_expired_and_nonpersistent_json(&mut file)
+        .map_err(|e| anyhow!("could not save cookies to {}: {}", path, e))?;
+    }
+    Ok(())
+  }
+}
+
+/// Creates (or truncates) a file only its owner can read, as it holds secrets such as session cookies
+fn create_private(path: &str) -> std::io::Result<fs::File> {
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let file = options.open(path)?;
+  // the mode only applies to new files, not to one saved before
+  #[cfg(unix)]
+  file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+  Ok(file)
+}
+
+/// Classifies a failed request, so that retry policies can tell the ones worth retrying
+fn request_error(err: reqwest::Error, message: String) -> Error {
+  if err.is_timeout() { retry::error(ErrorKind::Timeout, message) }
+  else if err.is_builder() { anyhow!(message) }
+  else { retry::error(ErrorKind::Connection, message) }
 }
 
-fn simple_get_response(url: &str, retries: u8, retry_secs: f64)
-  -> Result<reqwest::blocking::Response> {
-    
-    handle_retry(retries, retry_secs, || {
-      reqwest::blocking::get(url).map_err(|_| anyhow!("couldn't get URL via reqwest: {}",url))
-    })
+fn status_error(status: StatusCode, message: String) -> Error {
+  if status.is_server_error() { retry::error(ErrorKind::Http5xx, message) }
+  else if status.is_client_error() { retry::error(ErrorKind::Http4xx, message) }
+  else { anyhow!(message) }
+}
+
+/// Makes a request give up when the deadline is reached
+fn until(request: RequestBuilder, deadline: Option<Instant>) -> RequestBuilder {
+  match deadline {
+    Some(deadline) => request.timeout(deadline.saturating_duration_since(Instant::now())),
+    None => request
+  }
 }
 
 #[allow(dead_code)]
-pub fn simple_get_json(url: &str, retries: u8, retry_secs: f64) -> Result<Value> {
-  let body = simple_get_body(url, retries, retry_secs)?;
+pub fn simple_get_json(session: &HttpSession, url: &str, policy: &RetryPolicy, deadline: Option<Instant>) -> Result<Value> {
+  let body = simple_get_body(session, url, policy, deadline)?;
   serde_json::from_str(&body).map_err(|_| anyhow!("couldn't parse response body of {}. Body was: {}",url,&body))
 }
 
-pub fn simple_get_body(url: &str, retries: u8, retry_secs: f64) -> Result<String> {
-  let res = simple_get_response(url, retries, retry_secs)?;
-  if !res.status().is_success() {
-    bail!("error code on GET to URL {}: {}", url, res.status());
-  }
+pub fn simple_get_body(session: &HttpSession, url: &str, policy: &RetryPolicy, deadline: Option<Instant>) -> Result<String> {
+  let res = retry::retry(policy, || {
+    let res = until(session.client.get(url), deadline).send().map_err(|e| request_error(e, format!("couldn't get URL via reqwest: {}",url)))?;
+    if !res.status().is_success() {
+      return Err(status_error(res.status(), format!("error code on GET to URL {}: {}", url, res.status())));
+    }
+    Ok(res)
+  })?;
   res.text().map_err(|_| anyhow!("reqwest body error getting {}", url))
 }
 
-pub fn post_text(url: &str, headers: &HashMap<String,String>, body: &str, retries: u8, retry_secs: f64) -> Result<()> {
-  handle_retry(retries, retry_secs, || {
-    let mut post = reqwest::blocking::Client::new().post(url);
+pub fn post_text(session: &HttpSession, url: &str, headers: &HashMap<String,String>, body: &str, policy: &RetryPolicy, deadline: Option<Instant>) -> Result<()> {
+  retry::retry(policy, || {
+    let mut post = until(session.client.post(url), deadline);
     for (k,v) in headers {
       post = post.header(k,v);
     }
     post = post.body(body.to_string());
-    let response = post.send().map_err(|_| anyhow!("could not send POST request to {}", url))?;
+    let response = post.send().map_err(|e| request_error(e, format!("could not send POST request to {}", url)))?;
     if [StatusCode::OK, StatusCode::CREATED].contains(&response.status())
       { Ok(()) } 
     else 
-      { bail!("POST request to {} returned error code {}", url, response.status()) }
+      { Err(status_error(response.status(), format!("POST request to {} returned error code {}", url, response.status()))) }
   })
 }
 
@@ -62,10 +120,11 @@ pub fn post_text(url: &str, headers: &HashMap<String,String>, body: &str, retrie
 mod tests {
 
 use super::*;
+use std::time::Duration;
 
   #[test]
   fn test_get_invalid_url() {
-    let r = simple_get_body("http://www.123.45/a",1,0.5);
+    let r = simple_get_body(&HttpSession::new(None).unwrap(), "http://www.123.45/a", &RetryPolicy::fixed(1, Duration::from_millis(500)), None);
     let error = r.expect_err("get returned ok instead of Err with invalid url");
     log::debug!("{:?}",error);
     assert!(error.to_string().contains("couldn't get URL"));
@@ -73,7 +132,7 @@ use super::*;
 
   #[test]
   fn test_get_valid_url() {
-    let r = simple_get_body("https://jsonplaceholder.typicode.com/todos/1",1,0.5);
+    let r = simple_get_body(&HttpSession::new(None).unwrap(), "https://jsonplaceholder.typicode.com/todos/1", &RetryPolicy::fixed(1, Duration::from_millis(500)), None);
     assert!(r.is_ok());
   }
 
@@ -88,6 +147,24 @@ use super::*;
       "userId": 1
     }"#;
     let url = "https://jsonplaceholder.typicode.com/posts";
-    assert!(post_text(url,&hm,body,0,0.0).is_ok());
+    assert!(post_text(&HttpSession::new(None).unwrap(), url,&hm,body,&RetryPolicy::fixed(0, Duration::ZERO), None).is_ok());
+  }
+
+  #[test]
+  fn test_cookie_file_roundtrip() {
+    let path = std::env::temp_dir().join("m7m_test_cookies.json").to_string_lossy().to_string();
+    let _ = fs::remove_file(&path);
+    let url = reqwest::Url::parse("http://example.com/login").unwrap();
+
+    let session = HttpSession::new(Some(path.clone())).unwrap();
+    let _ = session.cookies.lock().unwrap().parse("session_id=abc; Path=/", &url);
+    session.save_cookies().unwrap();
+
+    #[cfg(unix)]
+    assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+
+    let reloaded = HttpSession::new(Some(path.clone())).unwrap();
+    assert!(reloaded.cookies.lock().unwrap().contains("example.com", "/", "session_id"));
+    let _ = fs::remove_file(&path);
   }
 }
@@ -0,0 +1,177 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use anyhow::{Result, anyhow, bail};
+
+use crate::yamlworkflow::DatetimeOptions;
+
+/// The time zone used to read and write timestamps that don't carry their own offset
+#[derive(Debug, Clone, Copy)]
+pub enum Zone {
+  Local,
+  Named(Tz),
+}
+
+impl Zone {
+  pub fn parse(timezone: Option<&str>) -> Result<Self> {
+    match timezone {
+      None => Ok(Zone::Local),
+      Some(name) => name.parse().map(Zone::Named).map_err(|_| anyhow!("unknown time zone: {}", name))
+    }
+  }
+
+  fn localize(&self, naive: NaiveDateTime) -> Result<DateTime<Utc>> {
+    let local = match self {
+      Zone::Local => Local.from_local_datetime(&naive).earliest().map(|d| d.with_timezone(&Utc)),
+      Zone::Named(tz) => tz.from_local_datetime(&naive).earliest().map(|d| d.with_timezone(&Utc)),
+    };
+    local.ok_or(anyhow!("time {} does not exist in the time zone", naive))
+  }
+}
+
+/// Reads a timestamp, either with an explicit strftime-like format or by trying usual formats:
+/// RFC 3339, RFC 2822, `YYYY-MM-DD HH:MM:SS`, `YYYY-MM-DD` and Unix timestamps in seconds or milliseconds
+pub fn parse(input: &str, format: Option<&str>, zone: Zone) -> Result<DateTime<Utc>> {
+  let input = input.trim();
+  if let Some(format) = format {
+    if format == "unix" { return parse_unix(input).ok_or(anyhow!("not a Unix timestamp: {}", input)); }
+    return DateTime::parse_from_str(input, format).map(|d| d.with_timezone(&Utc))
+      .or_else(|_| zone.localize(NaiveDateTime::parse_from_str(input, format)?))
+      .or_else(|_| zone.localize(NaiveDate::parse_from_str(input, format)?.and_hms_opt(0, 0, 0).unwrap_or_default()))
+      .map_err(|_| anyhow!("could not read date {} with format {}", input, format));
+  }
+  if let Ok(d) = DateTime::parse_from_rfc3339(input) { return Ok(d.with_timezone(&Utc)); }
+  if let Ok(d) = DateTime::parse_from_rfc2822(input) { return Ok(d.with_timezone(&Utc)); }
+  for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+    if let Ok(naive) = NaiveDateTime::parse_from_str(input, format) { return zone.localize(naive); }
+  }
+  if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+    return zone.localize(date.and_hms_opt(0, 0, 0).unwrap_or_default());
+  }
+  parse_unix(input).ok_or(anyhow!("could not read date: {}", input))
+}
+
+fn parse_unix(input: &str) -> Option<DateTime<Utc>> {
+  let number: i64 = input.parse().ok()?;
+  // beyond 10^11 seconds we're in year 5000+, so this must be milliseconds
+  if number.abs() >= 100_000_000_000 { DateTime::from_timestamp_millis(number) } else { DateTime::from_timestamp(number, 0) }
+}
+
+/// Writes a timestamp in a strftime-like format, `unix` for a Unix timestamp, or RFC 3339 by default
+pub fn format(time: &DateTime<Utc>, format: Option<&str>, zone: Zone) -> Result<String> {
+  let mut output = String::new();
+  // an invalid format is only detected while writing, where `to_string` would panic
+  let written = match (format, zone) {
+    (Some("unix"), _) => write!(output, "{}", time.timestamp()),
+    (Some(format), Zone::Local) => write!(output, "{}", time.with_timezone(&Local).format(format)),
+    (Some(format), Zone::Named(tz)) => write!(output, "{}", time.with_timezone(&tz).format(format)),
+    (None, Zone::Local) => write!(output, "{}", time.with_timezone(&Local).to_rfc3339()),
+    (None, Zone::Named(tz)) => write!(output, "{}", time.with_timezone(&tz).to_rfc3339()),
+  };
+  written.map_err(|_| anyhow!("invalid date format: {}", format.unwrap_or_default()))?;
+  Ok(output)
+}
+
+/// Reads a duration such as `26h` or `-1d 2h`, which may be negative
+pub fn parse_offset(offset: &str) -> Result<chrono::Duration> {
+  let offset = offset.trim();
+  let (negative, offset) = match offset.strip_prefix('-') {
+    Some(rest) => (true, rest),
+    None => (false, offset.strip_prefix('+').unwrap_or(offset))
+  };
+  let duration = parse_duration::parse(offset).map_err(|e| anyhow!("invalid duration {}: {}", offset, e))?;
+  let duration = chrono::Duration::from_std(duration).map_err(|_| anyhow!("duration too long: {}", offset))?;
+  Ok(if negative { -duration } else { duration })
+}
+
+/// Expresses a duration as a number of units (seconds, minutes, hours or days), with decimals
+pub fn in_unit(duration: chrono::Duration, unit: &str) -> Result<f64> {
+  let seconds = duration.num_milliseconds() as f64 / 1000.0;
+  Ok(match unit {
+    "seconds" => seconds,
+    "minutes" => seconds / 60.0,
+    "hours" => seconds / 3600.0,
+    "days" => seconds / 86400.0,
+    _ => bail!("unsupported time unit: {}", unit)
+  })
+}
+
+/// Runs a `datetime` step operation:
+/// - `now`: current time, plus the offset if any
+/// - `parse`: reads the input and writes it back in the output format, plus the offset if any
+/// - `diff`: time elapsed from the input to the other value (now by default), in the given unit
+/// - `before` / `after`: whether the input, plus the offset if any, is before/after the other value (now by default)
+pub fn operate(operation: &str, input: Option<&str>, other: Option<&str>, options: &DatetimeOptions) -> Result<String> {
+  let zone = Zone::parse(options.timezone.as_deref())?;
+  let offset = options.offset.as_deref().map(parse_offset).transpose()?.unwrap_or_default();
+  let input = || parse(input.unwrap_or_default(), options.input_format.as_deref(), zone);
+  let other = || other.map(|o| parse(o, options.input_format.as_deref(), zone)).unwrap_or(Ok(Utc::now()));
+  Ok(match operation {
+    "now" => format(&(Utc::now() + offset), options.format.as_deref(), zone)?,
+    "parse" => format(&(input()? + offset), options.format.as_deref(), zone)?,
+    "diff" => in_unit(other()? - input()?, options.unit.as_deref().unwrap_or("seconds"))?.to_string(),
+    "before" => (input()? + offset < other()?).to_string(),
+    "after" => (input()? + offset > other()?).to_string(),
+    _ => bail!("unsupported datetime operation: {}", operation)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn paris() -> Zone { Zone::parse(Some("Europe/Paris")).unwrap() }
+
+  #[test]
+  fn test_parse_usual_formats() {
+    let expected = Utc.with_ymd_and_hms(2023, 8, 1, 10, 30, 0).unwrap();
+    assert_eq!(parse("2023-08-01T12:30:00+02:00", None, Zone::Local).unwrap(), expected);
+    assert_eq!(parse("Tue, 1 Aug 2023 10:30:00 +0000", None, Zone::Local).unwrap(), expected);
+    assert_eq!(parse("2023-08-01 12:30:00", None, paris()).unwrap(), expected);
+    assert_eq!(parse("1690885800", None, Zone::Local).unwrap(), expected);
+    assert_eq!(parse("1690885800000", None, Zone::Local).unwrap(), expected);
+    assert!(parse("yesterday", None, Zone::Local).is_err());
+    assert!(Zone::parse(Some("Mars/Olympus")).is_err());
+  }
+
+  #[test]
+  fn test_parse_with_format() {
+    let expected = Utc.with_ymd_and_hms(2023, 8, 1, 10, 30, 0).unwrap();
+    assert_eq!(parse("01/08/2023 12:30", Some("%d/%m/%Y %H:%M"), paris()).unwrap(), expected);
+    assert_eq!(parse("1690885800", Some("unix"), paris()).unwrap(), expected);
+    assert!(parse("2023-08-01", Some("%d/%m/%Y"), paris()).is_err());
+  }
+
+  #[test]
+  fn test_format_and_offsets() {
+    let time = Utc.with_ymd_and_hms(2023, 8, 1, 10, 30, 0).unwrap();
+    assert_eq!(format(&time, None, paris()).unwrap(), "2023-08-01T12:30:00+02:00");
+    assert_eq!(format(&time, Some("%H:%M"), Zone::parse(Some("UTC")).unwrap()).unwrap(), "10:30");
+    assert_eq!(format(&time, Some("unix"), paris()).unwrap(), "1690885800");
+    assert_eq!(format(&(time + parse_offset("-26h").unwrap()), Some("%d %H"), paris()).unwrap(), "31 10");
+    assert_eq!(format(&time, Some("%Y %Q"), paris()).unwrap_err().to_string(), "invalid date format: %Y %Q");
+    assert!(format(&time, Some("%H:%M %"), Zone::Local).is_err());
+    assert_eq!(in_unit(parse_offset("90m").unwrap(), "hours").unwrap(), 1.5);
+    assert!(in_unit(parse_offset("1s").unwrap(), "fortnights").is_err());
+  }
+
+  #[test]
+  fn test_operations() {
+    let options = |offset: Option<&str>, unit: Option<&str>| DatetimeOptions {
+      input_format: None, format: None, timezone: Some("UTC".to_string()),
+      offset: offset.map(str::to_string), unit: unit.map(str::to_string),
+    };
+    let backup = Some("2023-08-01 10:30:00");
+    let checked = Some("2023-08-02 14:30:00");
+    assert_eq!(operate("diff", backup, checked, &options(None, Some("hours"))).unwrap(), "28");
+    assert_eq!(operate("before", backup, checked, &options(Some("26h"), None)).unwrap(), "true");
+    assert_eq!(operate("after", backup, checked, &options(Some("1d 6h"), None)).unwrap(), "true");
+    assert_eq!(operate("parse", backup, None, &options(Some("-30m"), None)).unwrap(), "2023-08-01T10:00:00+00:00");
+    assert_eq!(operate("before", backup, None, &options(None, None)).unwrap(), "true");
+    assert!(operate("parse", Some("soon"), None, &options(None, None)).is_err());
+    assert!(operate("tomorrow", None, None, &options(None, None)).is_err());
+    let bad_format = DatetimeOptions { format: Some("%Y %Q".to_string()), ..options(None, None) };
+    assert!(operate("now", None, None, &bad_format).is_err());
+  }
+}
@@ -0,0 +1,60 @@
+use similar::{ChangeTag, TextDiff};
+use anyhow::{Result, bail};
+
+/// The differences between two texts, as a readable diff and counts of changed lines
+#[derive(Debug, PartialEq)]
+pub struct Changes {
+  pub diff: String,
+  pub added: usize,
+  pub removed: usize,
+}
+
+/// Compares an old and a new text. The `unified` mode gives a unified diff with `context` lines around
+/// changes; the `words` mode gives the new text with changes marked as `[-removed-]{+added+}`.
+pub fn diff(old: &str, new: &str, mode: &str, context: usize) -> Result<Changes> {
+  let lines = TextDiff::from_lines(old, new);
+  let count = |tag| lines.iter_all_changes().filter(|c| c.tag() == tag).count();
+  let (added, removed) = (count(ChangeTag::Insert), count(ChangeTag::Delete));
+
+  let diff = match mode {
+    "unified" => lines.unified_diff().context_radius(context).header("previous", "current").to_string(),
+    "words" => TextDiff::from_words(old, new).iter_all_changes()
+      .map(|change| match change.tag() {
+        ChangeTag::Equal => change.value().to_string(),
+        ChangeTag::Delete => format!("[-{}-]", change.value()),
+        ChangeTag::Insert => format!("{{+{}+}}", change.value()),
+      })
+      .collect(),
+    _ => bail!("unsupported diff mode: {}", mode)
+  };
+  Ok(Changes { diff, added, removed })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static OLD: &str = "port 22\nuser admin\nlisten 0.0.0.0\n";
+  static NEW: &str = "port 2222\nuser admin\nlisten 0.0.0.0\nlog verbose\n";
+
+  #[test]
+  fn test_unified_diff() {
+    let changes = diff(OLD, NEW, "unified", 0).unwrap();
+    assert_eq!((changes.added, changes.removed), (2, 1));
+    assert_eq!(changes.diff, "--- previous\n+++ current\n@@ -1 +1 @@\n-port 22\n+port 2222\n@@ -3,0 +4 @@\n+log verbose\n");
+  }
+
+  #[test]
+  fn test_word_diff() {
+    let changes = diff("status is up", "status is down", "words", 3).unwrap();
+    assert_eq!(changes.diff, "status is [-up-]{+down+}");
+    assert_eq!((changes.added, changes.removed), (1, 1));
+  }
+
+  #[test]
+  fn test_no_changes() {
+    let changes = diff(OLD, OLD, "unified", 3).unwrap();
+    assert_eq!(changes, Changes { diff: String::new(), added: 0, removed: 0 });
+    assert!(diff(OLD, NEW, "side_by_side", 3).is_err());
+  }
+}
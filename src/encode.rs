@@ -0,0 +1,98 @@
+use base64::{Engine, engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}};
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use md5::Md5;
+use anyhow::{Result, anyhow, bail};
+
+fn hmac<M: Mac + KeyInit>(secret: Option<&str>, input: &str) -> Result<Vec<u8>> {
+  let secret = secret.ok_or(anyhow!("HMAC needs a secret"))?;
+  let mut mac = <M as KeyInit>::new_from_slice(secret.as_bytes()).map_err(|_| anyhow!("invalid HMAC secret"))?;
+  mac.update(input.as_bytes());
+  Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+  String::from_utf8(bytes).map_err(|_| anyhow!("decoded value is not valid UTF-8 text"))
+}
+
+fn html_escape(input: &str) -> String {
+  input.chars().fold(String::with_capacity(input.len()), |mut escaped, c| {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      _ => escaped.push(c)
+    }
+    escaped
+  })
+}
+
+/// Applies a hashing or encoding operation to a text. Digests and HMACs are written in hex,
+/// or in base64 if `digest_format` says so.
+pub fn encode(input: &str, operation: &str, secret: Option<&str>, digest_format: Option<&str>) -> Result<String> {
+  let digest = match operation {
+    "md5" => Md5::digest(input.as_bytes()).to_vec(),
+    "sha1" => Sha1::digest(input.as_bytes()).to_vec(),
+    "sha256" => Sha256::digest(input.as_bytes()).to_vec(),
+    "hmac_sha1" => hmac::<Hmac<Sha1>>(secret, input)?,
+    "hmac_sha256" => hmac::<Hmac<Sha256>>(secret, input)?,
+    _ => return Ok(match operation {
+      "base64" => STANDARD.encode(input),
+      "base64_decode" => utf8(STANDARD.decode(input.trim()).map_err(|_| anyhow!("input is not valid base64"))?)?,
+      "base64url" => URL_SAFE_NO_PAD.encode(input),
+      "base64url_decode" => utf8(URL_SAFE_NO_PAD.decode(input.trim().trim_end_matches('='))
+        .map_err(|_| anyhow!("input is not valid base64url"))?)?,
+      "hex" => hex::encode(input),
+      "hex_decode" => utf8(hex::decode(input.trim()).map_err(|_| anyhow!("input is not valid hex"))?)?,
+      "url_encode" => urlencoding::encode(input).into_owned(),
+      "url_decode" => urlencoding::decode(input).map_err(|_| anyhow!("URL-decoded value is not valid UTF-8 text"))?.into_owned(),
+      "html_escape" => html_escape(input),
+      _ => bail!("unsupported encoding operation: {}", operation)
+    })
+  };
+  match digest_format.unwrap_or("hex") {
+    "hex" => Ok(hex::encode(digest)),
+    "base64" => Ok(STANDARD.encode(digest)),
+    other => bail!("unsupported digest format: {}", other)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_digests() {
+    assert_eq!(encode("abc", "md5", None, None).unwrap(), "900150983cd24fb0d6963f7d28e17f72");
+    assert_eq!(encode("abc", "sha1", None, None).unwrap(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(encode("abc", "sha256", None, None).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(encode("abc", "sha256", None, Some("base64")).unwrap(), "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=");
+  }
+
+  #[test]
+  fn test_hmac() {
+    let message = "The quick brown fox jumps over the lazy dog";
+    assert_eq!(encode(message, "hmac_sha256", Some("key"), None).unwrap(), "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+    assert_eq!(encode(message, "hmac_sha1", Some("key"), None).unwrap(), "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9");
+    assert!(encode(message, "hmac_sha256", None, None).is_err());
+  }
+
+  #[test]
+  fn test_encodings() {
+    assert_eq!(encode("user:pass", "base64", None, None).unwrap(), "dXNlcjpwYXNz");
+    assert_eq!(encode("dXNlcjpwYXNz", "base64_decode", None, None).unwrap(), "user:pass");
+    assert_eq!(encode("a?>", "base64url", None, None).unwrap(), "YT8-");
+    assert_eq!(encode("YT8-", "base64url_decode", None, None).unwrap(), "a?>");
+    assert_eq!(encode("hi", "hex", None, None).unwrap(), "6869");
+    assert_eq!(encode("6869", "hex_decode", None, None).unwrap(), "hi");
+    assert_eq!(encode("a b&c=d", "url_encode", None, None).unwrap(), "a%20b%26c%3Dd");
+    assert_eq!(encode("a%20b%26c", "url_decode", None, None).unwrap(), "a b&c");
+    assert_eq!(encode("<a href=\"x\">O'Neil & co</a>", "html_escape", None, None).unwrap(),
+      "&lt;a href=&quot;x&quot;&gt;O&#39;Neil &amp; co&lt;/a&gt;");
+    assert!(encode("zz", "hex_decode", None, None).is_err());
+    assert!(encode("x", "rot13", None, None).is_err());
+  }
+}
@@ -0,0 +1,354 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::compare;
+
+/// A value computed by an expression. Variables are always text, and get converted to numbers when
+/// used in arithmetic.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Number(f64),
+  Text(String),
+  Bool(bool),
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      // integers print without a decimal point, so that results can be reused as counters, indexes...
+      Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+      Value::Number(n) => write!(f, "{}", n),
+      Value::Text(s) => write!(f, "{}", s),
+      Value::Bool(b) => write!(f, "{}", b),
+    }
+  }
+}
+
+impl Value {
+  pub fn as_number(&self) -> Result<f64> {
+    match self {
+      Value::Number(n) => Ok(*n),
+      Value::Text(s) => s.trim().parse().map_err(|_| anyhow!("value is not numeric: {}", s)),
+      Value::Bool(_) => bail!("a boolean cannot be used as a number"),
+    }
+  }
+
+  pub fn as_bool(&self) -> Result<bool> {
+    match self {
+      Value::Bool(b) => Ok(*b),
+      Value::Text(s) if s == "true" => Ok(true),
+      Value::Text(s) if s == "false" => Ok(false),
+      other => bail!("value is not a boolean: {}", other),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Number(f64),
+  Text(String),
+  Ident(String),
+  Symbol(&'static str),
+}
+
+/// Comparisons written as words, e.g. `status in 'up,degraded'`, evaluated as in `compare_var`
+const WORD_OPERATORS: [&str; 12] = [
+  "contains", "starts_with", "ends_with", "matches", "in", "between",
+  "version_eq", "version_gt", "version_gte", "version_lt", "version_lte", "version_matches",
+];
+
+const SYMBOLS: [&str; 15] = ["==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",", "!"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+  let mut tokens = vec![];
+  let chars: Vec<char> = input.chars().collect();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    if c.is_whitespace() { i += 1; continue; }
+    if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') { i += 1; }
+      let number: String = chars[start..i].iter().collect();
+      tokens.push(Token::Number(number.parse().map_err(|_| anyhow!("invalid number in expression: {}", number))?));
+    } else if c.is_alphabetic() || c == '_' {
+      let start = i;
+      while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') { i += 1; }
+      tokens.push(Token::Ident(chars[start..i].iter().collect()));
+    } else if c == '\'' || c == '"' {
+      let end = chars[i + 1..].iter().position(|d| *d == c).ok_or(anyhow!("unterminated string in expression: {}", input))?;
+      tokens.push(Token::Text(chars[i + 1..i + 1 + end].iter().collect()));
+      i += end + 2;
+    } else {
+      let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+      let symbol = SYMBOLS.iter().find(|s| rest.starts_with(**s)).ok_or(anyhow!("unexpected character {} in expression: {}", c, input))?;
+      tokens.push(Token::Symbol(symbol));
+      i += symbol.len();
+    }
+  }
+  Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+  Literal(Value),
+  Variable(String),
+  Negate(Box<Node>),
+  Not(Box<Node>),
+  Binary(String, Box<Node>, Box<Node>),
+  Call(String, Vec<Node>),
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    self.pos += 1;
+    self.tokens.get(self.pos - 1).cloned()
+  }
+
+  /// Consumes the next token if it is one of the given operators, symbols or keywords alike
+  fn accept(&mut self, operators: &[&str]) -> Option<String> {
+    let operator = match self.peek() {
+      Some(Token::Symbol(s)) if operators.contains(s) => s.to_string(),
+      Some(Token::Ident(s)) if operators.contains(&s.as_str()) => s.clone(),
+      _ => return None
+    };
+    self.pos += 1;
+    Some(operator)
+  }
+
+  fn expect(&mut self, symbol: &str) -> Result<()> {
+    match self.next() {
+      Some(Token::Symbol(s)) if s == symbol => Ok(()),
+      other => bail!("expected {} in expression, found {:?}", symbol, other)
+    }
+  }
+
+  fn binary_level(&mut self, operators: &[&str], operand: fn(&mut Self) -> Result<Node>) -> Result<Node> {
+    let mut node = operand(self)?;
+    while let Some(op) = self.accept(operators) {
+      node = Node::Binary(op, Box::new(node), Box::new(operand(self)?));
+    }
+    Ok(node)
+  }
+
+  fn or(&mut self) -> Result<Node> { self.binary_level(&["or"], Self::and) }
+
+  fn and(&mut self) -> Result<Node> { self.binary_level(&["and"], Self::not) }
+
+  fn not(&mut self) -> Result<Node> {
+    match self.accept(&["not", "!"]) {
+      Some(_) => Ok(Node::Not(Box::new(self.not()?))),
+      None => self.comparison()
+    }
+  }
+
+  fn comparison(&mut self) -> Result<Node> {
+    let left = self.additive()?;
+    match self.accept(&["==", "!=", "<", "<=", ">", ">="]).or_else(|| self.accept(&WORD_OPERATORS)) {
+      Some(op) => Ok(Node::Binary(op, Box::new(left), Box::new(self.additive()?))),
+      None => Ok(left)
+    }
+  }
+
+  fn additive(&mut self) -> Result<Node> { self.binary_level(&["+", "-"], Self::term) }
+
+  fn term(&mut self) -> Result<Node> { self.binary_level(&["*", "/", "%"], Self::unary) }
+
+  fn unary(&mut self) -> Result<Node> {
+    match self.accept(&["-"]) {
+      Some(_) => Ok(Node::Negate(Box::new(self.unary()?))),
+      None => self.primary()
+    }
+  }
+
+  fn primary(&mut self) -> Result<Node> {
+    match self.next() {
+      Some(Token::Number(n)) => Ok(Node::Literal(Value::Number(n))),
+      Some(Token::Text(s)) => Ok(Node::Literal(Value::Text(s))),
+      Some(Token::Ident(s)) if s == "true" || s == "false" => Ok(Node::Literal(Value::Bool(s == "true"))),
+      Some(Token::Ident(name)) => {
+        if self.accept(&["("]).is_none() { return Ok(Node::Variable(name)); }
+        let mut args = vec![];
+        if self.accept(&[")"]).is_none() {
+          loop {
+            args.push(self.or()?);
+            if self.accept(&[","]).is_none() { break; }
+          }
+          self.expect(")")?;
+        }
+        Ok(Node::Call(name, args))
+      },
+      Some(Token::Symbol("(")) => {
+        let node = self.or()?;
+        self.expect(")")?;
+        Ok(node)
+      },
+      other => bail!("unexpected {:?} in expression", other)
+    }
+  }
+}
+
+/// A parsed expression, ready to be evaluated against the variables of a flow
+#[derive(Debug, Clone)]
+pub struct Expression {
+  root: Node,
+}
+
+impl Expression {
+  pub fn parse(input: &str) -> Result<Self> {
+    let mut parser = Parser { tokens: tokenize(input)?, pos: 0 };
+    let root = parser.or()?;
+    if let Some(token) = parser.peek() {
+      bail!("unexpected {:?} in expression: {}", token, input);
+    }
+    Ok(Self { root })
+  }
+
+  pub fn evaluate(&self, vars: &HashMap<String, String>) -> Result<Value> {
+    eval(&self.root, vars)
+  }
+}
+
+/// Parses and evaluates an expression in one go
+pub fn evaluate(input: &str, vars: &HashMap<String, String>) -> Result<Value> {
+  Expression::parse(input)?.evaluate(vars)
+}
+
+/// Equality compares numbers numerically when both sides are numeric, as text otherwise
+fn equals(left: &Value, right: &Value) -> bool {
+  match (left.as_number(), right.as_number()) {
+    (Ok(l), Ok(r)) => l == r,
+    _ => left.to_string() == right.to_string()
+  }
+}
+
+fn eval(node: &Node, vars: &HashMap<String, String>) -> Result<Value> {
+  Ok(match node {
+    Node::Literal(value) => value.clone(),
+    Node::Variable(name) => Value::Text(vars.get(name).ok_or(anyhow!("unknown variable in expression: {}", name))?.clone()),
+    Node::Negate(inner) => Value::Number(-eval(inner, vars)?.as_number()?),
+    Node::Not(inner) => Value::Bool(!eval(inner, vars)?.as_bool()?),
+    Node::Binary(op, left, right) if op == "and" => Value::Bool(eval(left, vars)?.as_bool()? && eval(right, vars)?.as_bool()?),
+    Node::Binary(op, left, right) if op == "or" => Value::Bool(eval(left, vars)?.as_bool()? || eval(right, vars)?.as_bool()?),
+    Node::Binary(op, left, right) => {
+      let (left, right) = (eval(left, vars)?, eval(right, vars)?);
+      match op.as_str() {
+        "==" => Value::Bool(equals(&left, &right)),
+        "!=" => Value::Bool(!equals(&left, &right)),
+        "<" => Value::Bool(left.as_number()? < right.as_number()?),
+        "<=" => Value::Bool(left.as_number()? <= right.as_number()?),
+        ">" => Value::Bool(left.as_number()? > right.as_number()?),
+        ">=" => Value::Bool(left.as_number()? >= right.as_number()?),
+        "+" => Value::Number(left.as_number()? + right.as_number()?),
+        "-" => Value::Number(left.as_number()? - right.as_number()?),
+        "*" => Value::Number(left.as_number()? * right.as_number()?),
+        "/" | "%" if right.as_number()? == 0.0 => bail!("division by zero in expression"),
+        "/" => Value::Number(left.as_number()? / right.as_number()?),
+        "%" => Value::Number(left.as_number()? % right.as_number()?),
+        _ => Value::Bool(compare::compare(&left.to_string(), op, &right.to_string())?)
+      }
+    },
+    Node::Call(name, args) => {
+      let args = args.iter().map(|a| eval(a, vars)).collect::<Result<Vec<Value>>>()?;
+      call(name, &args)?
+    }
+  })
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value> {
+  if name == "is_empty" {
+    return match args {
+      [arg] => Ok(Value::Bool(arg.to_string().trim().is_empty())),
+      _ => bail!("is_empty() takes exactly one argument")
+    };
+  }
+  let numbers = || args.iter().map(Value::as_number).collect::<Result<Vec<f64>>>();
+  let single = || match args {
+    [arg] => arg.as_number(),
+    _ => bail!("{}() takes exactly one argument", name)
+  };
+  Ok(Value::Number(match name {
+    "abs" => single()?.abs(),
+    "floor" => single()?.floor(),
+    "ceil" => single()?.ceil(),
+    "round" => match numbers()?.as_slice() {
+      [x] => x.round(),
+      [x, digits] => {
+        let factor = 10f64.powi(*digits as i32);
+        (x * factor).round() / factor
+      },
+      _ => bail!("round() takes a value and optionally a number of digits")
+    },
+    "min" | "max" => {
+      let numbers = numbers()?;
+      if numbers.is_empty() { bail!("{}() needs at least one argument", name); }
+      let fold: fn(f64, f64) -> f64 = if name == "min" { f64::min } else { f64::max };
+      numbers.into_iter().reduce(fold).unwrap_or_default()
+    },
+    _ => bail!("unknown function in expression: {}", name)
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn vars() -> HashMap<String, String> {
+    HashMap::from([
+      ("used".to_string(), "45".to_string()),
+      ("total".to_string(), " 60 ".to_string()),
+      ("status".to_string(), "up".to_string()),
+    ])
+  }
+
+  fn eval_str(input: &str) -> String {
+    evaluate(input, &vars()).unwrap().to_string()
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    assert_eq!(eval_str("(used / total) * 100"), "75");
+    assert_eq!(eval_str("1 + 2 * 3 - -1"), "8");
+    assert_eq!(eval_str("10 % 4 / 4"), "0.5");
+    assert_eq!(eval_str("round(2 / 3, 2)"), "0.67");
+    assert_eq!(eval_str("round(used / 10)"), "5");
+    assert_eq!(eval_str("min(used, total, 50) + max(1, 2) + abs(-1)"), "48");
+    assert_eq!(eval_str("floor(1.7) + ceil(1.2)"), "3");
+  }
+
+  #[test]
+  fn test_logic() {
+    assert_eq!(eval_str("used > 40 and status == 'up'"), "true");
+    assert_eq!(eval_str("not used < total or status != \"up\""), "false");
+    assert_eq!(eval_str("total == 60.0"), "true");
+  }
+
+  #[test]
+  fn test_word_comparisons() {
+    assert_eq!(eval_str("status in 'up, degraded' and used between '40..50'"), "true");
+    assert_eq!(eval_str("not status matches '^d' or status contains 'x'"), "true");
+    assert_eq!(eval_str("'1.10' version_gt '1.9' and status starts_with 'u'"), "true");
+    assert_eq!(eval_str("is_empty(status) or is_empty('  ')"), "true");
+    assert!(evaluate("status between '1..2'", &vars()).is_err());
+  }
+
+  #[test]
+  fn test_errors() {
+    assert!(evaluate("status * 2", &vars()).is_err());
+    assert!(evaluate("missing + 1", &vars()).is_err());
+    assert!(evaluate("used / 0", &vars()).is_err());
+    assert!(evaluate("(used + 1", &vars()).is_err());
+    assert!(evaluate("used used", &vars()).is_err());
+    assert!(evaluate("sqrt(used)", &vars()).is_err());
+  }
+}
@@ -0,0 +1,58 @@
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use anyhow::{Result, anyhow, bail};
+
+/// Parses a text as JSON, to be queried with `query`
+pub fn parse(input: &str) -> Result<Value> {
+  serde_json::from_str(input).map_err(|e| anyhow!("input is not valid JSON: {}", e))
+}
+
+/// Turns a JSON value into a variable: strings are stored as-is, anything else as JSON text
+pub fn value_to_string(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    other => other.to_string()
+  }
+}
+
+/// Evaluates a JSONPath expression (RFC 9535) against a document.
+/// A single match is returned as a plain value, several matches as a JSON array.
+pub fn query(document: &Value, path: &str) -> Result<String> {
+  let json_path = JsonPath::parse(path).map_err(|e| anyhow!("invalid JSON path {}: {}", path, e))?;
+  let nodes = json_path.query(document).all();
+  match nodes.len() {
+    0 => bail!("JSON path {} not found in input", path),
+    1 => Ok(value_to_string(nodes[0])),
+    _ => Ok(Value::Array(nodes.into_iter().cloned().collect()).to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static DOC: &str = r#"{"status": "up", "load": 0.5, "disks": [{"name": "sda", "free": 10}, {"name": "sdb", "free": 20}]}"#;
+
+  #[test]
+  fn test_query_single_values() {
+    let doc = parse(DOC).unwrap();
+    assert_eq!(query(&doc, "$.status").unwrap(), "up");
+    assert_eq!(query(&doc, "$.load").unwrap(), "0.5");
+    assert_eq!(query(&doc, "$.disks[1].name").unwrap(), "sdb");
+  }
+
+  #[test]
+  fn test_query_several_matches() {
+    let doc = parse(DOC).unwrap();
+    assert_eq!(query(&doc, "$.disks[*].free").unwrap(), "[10,20]");
+    assert_eq!(query(&doc, "$.disks[?@.free > 15].name").unwrap(), "sdb");
+  }
+
+  #[test]
+  fn test_query_missing_path() {
+    let doc = parse(DOC).unwrap();
+    assert!(query(&doc, "$.uptime").is_err());
+    assert!(query(&doc, "not a path").is_err());
+    assert!(parse("<html>").is_err());
+  }
+}
@@ -4,6 +4,18 @@ mod data;
 mod yamlworkflow;
 mod workflow;
 mod files;
+mod json;
+mod prometheus;
+mod markup;
+mod tables;
+mod compare;
+mod expr;
+mod datetime;
+mod encode;
+mod persist;
+mod diff;
+mod text;
+mod retry;
 use std::env;
 use anyhow::Result;
 
@@ -49,7 +61,7 @@ fn parse_cli() -> CLIConfig {
   }
 }
 
-fn select_flow(flow_name: &Option<String>, flow_names: &Vec<String>)  -> bool {
+fn select_flow(flow_name: &Option<String>, flow_names: &[String])  -> bool {
   if flow_names.is_empty() {true}
   else {
     match flow_name {
@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use scraper::{Html, Selector};
+use anyhow::{Result, anyhow, bail};
+
+use crate::text::to_list;
+
+/// Returns the single first value, or all of them as a JSON list, failing when there is none
+fn first_or_list(mut values: Vec<String>, all_matches: bool, what: &str) -> Result<String> {
+  if all_matches { return Ok(to_list(values)); }
+  if values.is_empty() { bail!("nothing found for {}", what); }
+  Ok(values.swap_remove(0))
+}
+
+/// Selects elements of an HTML page with a CSS selector, and returns their text or one of their attributes.
+/// Text is whitespace-normalized, as pages are usually indented.
+pub fn html_extract(input: &str, selector: &str, attribute: Option<&str>, all_matches: bool) -> Result<String> {
+  let css = Selector::parse(selector).map_err(|e| anyhow!("invalid CSS selector {}: {}", selector, e))?;
+  let document = Html::parse_document(input);
+  let values = document.select(&css)
+    .filter_map(|element| match attribute {
+      Some(attribute) => element.value().attr(attribute).map(str::to_string),
+      None => Some(element.text().flat_map(str::split_whitespace).collect::<Vec<_>>().join(" "))
+    })
+    .collect();
+  first_or_list(values, all_matches, selector)
+}
+
+/// Evaluates an XPath 1.0 expression on an XML document. Node sets give the text value of their nodes,
+/// other results (numbers, booleans, strings) are returned as-is.
+pub fn xml_extract(input: &str, xpath: &str, namespaces: &BTreeMap<String, String>, all_matches: bool) -> Result<String> {
+  let package = sxd_document::parser::parse(input).map_err(|e| anyhow!("input is not valid XML: {:?}", e))?;
+  let document = package.as_document();
+  let compiled = sxd_xpath::Factory::new().build(xpath)
+    .map_err(|e| anyhow!("invalid XPath {}: {}", xpath, e))?
+    .ok_or(anyhow!("empty XPath expression"))?;
+  let mut context = sxd_xpath::Context::new();
+  for (prefix, uri) in namespaces {
+    context.set_namespace(prefix, uri);
+  }
+  let value = compiled.evaluate(&context, document.root())
+    .map_err(|e| anyhow!("could not evaluate XPath {}: {}", xpath, e))?;
+  match value {
+    sxd_xpath::Value::Nodeset(nodes) => {
+      let values = nodes.document_order().iter().map(|n| n.string_value()).collect();
+      first_or_list(values, all_matches, xpath)
+    },
+    other => Ok(other.string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static PAGE: &str = r#"<html><body>
+    <table id="status">
+      <tr><td class="name">WAN</td><td class="value">
+        Connected
+      </td></tr>
+      <tr><td class="name">DSL</td><td class="value">Down</td></tr>
+    </table>
+    <a href="/reboot">Reboot</a>
+  </body></html>"#;
+
+  static FEED: &str = r#"<?xml version="1.0"?>
+    <rss><channel>
+      <item><title>First</title><link>http://a</link></item>
+      <item><title>Second</title><link>http://b</link></item>
+    </channel></rss>"#;
+
+  #[test]
+  fn test_html_extract() {
+    assert_eq!(html_extract(PAGE, "#status td.value", None, false).unwrap(), "Connected");
+    assert_eq!(html_extract(PAGE, "td.value", None, true).unwrap(), r#"["Connected","Down"]"#);
+    assert_eq!(html_extract(PAGE, "a", Some("href"), false).unwrap(), "/reboot");
+    assert!(html_extract(PAGE, "span.missing", None, false).is_err());
+    assert!(html_extract(PAGE, "td[", None, false).is_err());
+  }
+
+  #[test]
+  fn test_xml_extract() {
+    let none = BTreeMap::new();
+    assert_eq!(xml_extract(FEED, "/rss/channel/item[2]/title", &none, false).unwrap(), "Second");
+    assert_eq!(xml_extract(FEED, "//link", &none, true).unwrap(), r#"["http://a","http://b"]"#);
+    assert_eq!(xml_extract(FEED, "count(//item)", &none, false).unwrap(), "2");
+    assert!(xml_extract(FEED, "//missing", &none, false).is_err());
+    assert!(xml_extract("<html>", "//a", &none, false).is_err());
+  }
+
+  #[test]
+  fn test_xml_extract_namespaces() {
+    let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>News</title></feed>"#;
+    let namespaces = BTreeMap::from([("a".to_string(), "http://www.w3.org/2005/Atom".to_string())]);
+    assert_eq!(xml_extract(atom, "/a:feed/a:title", &namespaces, false).unwrap(), "News");
+  }
+}
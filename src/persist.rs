@@ -0,0 +1,115 @@
+use std::{collections::{HashMap, HashSet}, fs, sync::{Mutex, OnceLock}};
+
+use anyhow::{Result, anyhow};
+
+/// Values kept on disk from one run of a flow to the next, as a JSON object.
+/// Values recorded during a run are only visible as "previous" values from the next run on.
+pub struct Store {
+  path: String,
+  previous: Mutex<HashMap<String, String>>,
+  recorded: Mutex<HashMap<String, String>>,
+}
+
+/// Paths of the stores opened so far, to spot flows sharing one
+static OPENED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+/// Held while a store file is read and written back, as several stores may share one
+static WRITING: Mutex<()> = Mutex::new(());
+
+fn read(path: &str) -> Result<HashMap<String, String>> {
+  match fs::read_to_string(path) {
+    Ok(contents) => serde_json::from_str(&contents).map_err(|_| anyhow!("state file {} is not valid", path)),
+    Err(_) => Ok(HashMap::new())
+  }
+}
+
+impl Store {
+  /// Opens the store kept at `path`; a missing file is an empty store
+  pub fn open(path: String) -> Result<Self> {
+    let previous = read(&path)?;
+    if !OPENED.get_or_init(Default::default).lock().unwrap().insert(path.clone()) {
+      log::warn!("state file {} is used by several flows, whose values clash if they use the same names; give each flow its own name or state_file", path);
+    }
+    Ok(Self { path, previous: Mutex::new(previous), recorded: Mutex::new(HashMap::new()) })
+  }
+
+  /// The default file for a flow, named after it
+  pub fn default_path(flow_name: &str) -> String {
+    let name: String = flow_name.chars().map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    format!("{}.state.json", name)
+  }
+
+  /// The value saved by the previous run, if any
+  pub fn previous(&self, key: &str) -> Option<String> {
+    self.previous.lock().unwrap().get(key).cloned()
+  }
+
+  /// Records a value to be saved at the end of the run
+  pub fn record(&self, key: &str, value: &str) {
+    self.recorded.lock().unwrap().insert(key.to_string(), value.to_string());
+  }
+
+  /// Saves the values recorded during the run, which become the previous values of the next run.
+  /// Nothing is written if nothing was recorded. Values saved to the same file by other stores are kept, and
+  /// the file is replaced at once, so that a crash cannot leave it half written.
+  pub fn commit(&self) -> Result<()> {
+    let recorded: Vec<(String, String)> = self.recorded.lock().unwrap().drain().collect();
+    if recorded.is_empty() { return Ok(()); }
+    let _writing = WRITING.lock().unwrap();
+    let mut previous = self.previous.lock().unwrap();
+    let mut saved = read(&self.path).unwrap_or_else(|_| previous.clone());
+    saved.extend(recorded);
+    let contents = serde_json::to_string_pretty(&saved)?;
+    let temp_path = format!("{}.tmp", self.path);
+    fs::write(&temp_path, contents).and_then(|()| fs::rename(&temp_path, &self.path))
+      .map_err(|_| anyhow!("could not write state file {}", self.path))?;
+    *previous = saved;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_store_roundtrip() {
+    let path = std::env::temp_dir().join("m7m_test_store.json").to_string_lossy().to_string();
+    let _ = fs::remove_file(&path);
+
+    let store = Store::open(path.clone()).unwrap();
+    assert_eq!(store.previous("status"), None);
+    store.record("status", "up");
+    assert_eq!(store.previous("status"), None);
+    store.commit().unwrap();
+    assert_eq!(store.previous("status").as_deref(), Some("up"));
+
+    let reopened = Store::open(path.clone()).unwrap();
+    assert_eq!(reopened.previous("status").as_deref(), Some("up"));
+    let _ = fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_shared_file() {
+    let path = std::env::temp_dir().join("m7m_test_shared_store.json").to_string_lossy().to_string();
+    let _ = fs::remove_file(&path);
+
+    let first = Store::open(path.clone()).unwrap();
+    let second = Store::open(path.clone()).unwrap();
+    first.record("web", "up");
+    first.commit().unwrap();
+    second.record("db", "down");
+    second.commit().unwrap();
+
+    let reopened = Store::open(path.clone()).unwrap();
+    assert_eq!(reopened.previous("web").as_deref(), Some("up"));
+    assert_eq!(reopened.previous("db").as_deref(), Some("down"));
+    assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    let _ = fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_default_path() {
+    assert_eq!(Store::default_path("my flow/1"), "my_flow_1.state.json");
+  }
+}
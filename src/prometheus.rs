@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use anyhow::{Result, anyhow, bail};
+
+/// One series value read from a Prometheus text exposition
+#[derive(Debug, PartialEq)]
+pub struct Sample {
+  pub name: String,
+  pub labels: HashMap<String, String>,
+  pub value: f64,
+}
+
+enum LabelMatcher {
+  Equal(String, String),
+  NotEqual(String, String),
+  Matches(String, Regex),
+  NotMatches(String, Regex),
+}
+
+impl LabelMatcher {
+  fn matches(&self, labels: &HashMap<String, String>) -> bool {
+    // as in PromQL, a missing label behaves like an empty one
+    let get = |name: &String| labels.get(name).map(|v| v.as_str()).unwrap_or("");
+    match self {
+      LabelMatcher::Equal(name, value) => get(name) == value,
+      LabelMatcher::NotEqual(name, value) => get(name) != value,
+      LabelMatcher::Matches(name, re) => re.is_match(get(name)),
+      LabelMatcher::NotMatches(name, re) => !re.is_match(get(name)),
+    }
+  }
+}
+
+/// A PromQL-like series selector such as `node_filesystem_avail_bytes{mountpoint="/",fstype!~"tmpfs|ramfs"}`
+pub struct Selector {
+  name: String,
+  matchers: Vec<LabelMatcher>,
+}
+
+impl Selector {
+  pub fn parse(selector: &str) -> Result<Self> {
+    let selector = selector.trim();
+    let (name, rest) = split_name(selector);
+    if name.is_empty() { bail!("selector has no metric name: {}", selector); }
+    let mut matchers = vec![];
+    for (label, op, value) in parse_labels(rest.trim())? {
+      let anchored = || Regex::new(&format!("^(?:{})$", value)).map_err(|_| anyhow!("invalid regex in selector: {}", value));
+      matchers.push(match op.as_str() {
+        "=" => LabelMatcher::Equal(label, value),
+        "!=" => LabelMatcher::NotEqual(label, value),
+        "=~" => LabelMatcher::Matches(label, anchored()?),
+        "!~" => LabelMatcher::NotMatches(label, anchored()?),
+        _ => bail!("unsupported label matcher {} in selector {}", op, selector)
+      });
+    }
+    Ok(Self { name: name.to_string(), matchers })
+  }
+
+  pub fn matches(&self, sample: &Sample) -> bool {
+    sample.name == self.name && self.matchers.iter().all(|m| m.matches(&sample.labels))
+  }
+}
+
+fn split_name(s: &str) -> (&str, &str) {
+  let end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':')).unwrap_or(s.len());
+  s.split_at(end)
+}
+
+/// Parses `{label="value",...}` at the start of the input, returning (label, operator, value) triples.
+/// Returns an empty list when the input does not start with a brace.
+fn parse_labels(s: &str) -> Result<Vec<(String, String, String)>> {
+  let mut labels = vec![];
+  let mut chars = s.chars().peekable();
+  if chars.peek() != Some(&'{') { return Ok(labels); }
+  chars.next();
+  loop {
+    while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') { chars.next(); }
+    if chars.peek() == Some(&'}') { break; }
+    let mut name = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+      name.push(*c);
+      chars.next();
+    }
+    if name.is_empty() { bail!("invalid label name in {}", s); }
+    let mut op = String::new();
+    while let Some(c) = chars.peek().filter(|c| ['=', '!', '~'].contains(c)) {
+      op.push(*c);
+      chars.next();
+    }
+    if chars.next() != Some('"') { bail!("expected a quoted value for label {} in {}", name, s); }
+    let mut value = String::new();
+    loop {
+      match chars.next() {
+        Some('"') => break,
+        Some('\\') => match chars.next() {
+          Some('n') => value.push('\n'),
+          Some(c) => value.push(c),
+          None => bail!("unterminated label value in {}", s)
+        },
+        Some(c) => value.push(c),
+        None => bail!("unterminated label value in {}", s)
+      }
+    }
+    labels.push((name, op, value));
+  }
+  Ok(labels)
+}
+
+fn parse_value(s: &str) -> Result<f64> {
+  match s {
+    "+Inf" | "Inf" => Ok(f64::INFINITY),
+    "-Inf" => Ok(f64::NEG_INFINITY),
+    "NaN" => Ok(f64::NAN),
+    _ => s.parse().map_err(|_| anyhow!("invalid sample value: {}", s))
+  }
+}
+
+/// Parses the Prometheus text exposition format, ignoring comments (`# HELP`, `# TYPE`) and timestamps
+pub fn parse_exposition(text: &str) -> Result<Vec<Sample>> {
+  let mut samples = vec![];
+  for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+    let (name, rest) = split_name(line);
+    let rest = rest.trim_start();
+    let (labels, rest) = if rest.starts_with('{') {
+      let end = closing_brace(rest).ok_or(anyhow!("unterminated label set in line: {}", line))?;
+      (parse_labels(&rest[..=end])?, &rest[end + 1..])
+    } else {
+      (vec![], rest)
+    };
+    let value = rest.split_whitespace().next().ok_or(anyhow!("no value in line: {}", line))?;
+    samples.push(Sample {
+      name: name.to_string(),
+      labels: labels.into_iter().map(|(k, _, v)| (k, v)).collect(),
+      value: parse_value(value)?,
+    });
+  }
+  Ok(samples)
+}
+
+/// Finds the brace closing a label set, skipping over quoted values
+fn closing_brace(s: &str) -> Option<usize> {
+  let mut in_quotes = false;
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match (c, in_quotes, escaped) {
+      (_, true, true) => escaped = false,
+      ('\\', true, false) => escaped = true,
+      ('"', _, false) => in_quotes = !in_quotes,
+      ('}', false, _) => return Some(i),
+      _ => {}
+    }
+  }
+  None
+}
+
+/// Combines the values of several series into one
+pub fn aggregate(values: &[f64], aggregation: &str) -> Result<f64> {
+  if values.is_empty() && aggregation != "count" {
+    bail!("cannot compute {} of no values", aggregation);
+  }
+  Ok(match aggregation {
+    "sum" => values.iter().sum(),
+    "avg" => values.iter().sum::<f64>() / values.len() as f64,
+    "min" => values.iter().cloned().fold(f64::INFINITY, f64::min),
+    "max" => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+    "count" => values.len() as f64,
+    _ => bail!("unsupported aggregation: {}", aggregation)
+  })
+}
+
+/// Selects the series matching `selector` in an exposition text and returns their value.
+/// Several matching series are only accepted when an aggregation is given.
+pub fn extract(text: &str, selector: &str, aggregation: Option<&str>) -> Result<f64> {
+  let parsed_selector = Selector::parse(selector)?;
+  let values: Vec<f64> = parse_exposition(text)?.iter()
+    .filter(|s| parsed_selector.matches(s))
+    .map(|s| s.value)
+    .collect();
+  if values.is_empty() && aggregation != Some("count") {
+    bail!("no series matching {}", selector);
+  }
+  match (aggregation, values.len()) {
+    (Some(aggregation), _) => aggregate(&values, aggregation),
+    (None, 1) => Ok(values[0]),
+    (None, n) => bail!("{} series match {}, an aggregation is needed", n, selector)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static METRICS: &str = r#"
+# HELP node_filesystem_avail_bytes Filesystem space available.
+# TYPE node_filesystem_avail_bytes gauge
+node_filesystem_avail_bytes{device="/dev/sda1",fstype="ext4",mountpoint="/"} 1000
+node_filesystem_avail_bytes{device="/dev/sdb1",fstype="ext4",mountpoint="/data"} 3000
+node_filesystem_avail_bytes{device="tmpfs",fstype="tmpfs",mountpoint="/run"} 500 1690000000000
+status 1
+weird{path="a \"quoted\" }value"} NaN
+"#;
+
+  #[test]
+  fn test_parse_exposition() {
+    let samples = parse_exposition(METRICS).unwrap();
+    assert_eq!(samples.len(), 5);
+    assert_eq!(samples[2].labels["mountpoint"], "/run");
+    assert_eq!(samples[2].value, 500.0);
+    assert_eq!(samples[3].name, "status");
+    assert!(samples[3].labels.is_empty());
+    assert_eq!(samples[4].labels["path"], "a \"quoted\" }value");
+    assert!(samples[4].value.is_nan());
+  }
+
+  #[test]
+  fn test_extract_single_series() {
+    assert_eq!(extract(METRICS, "status", None).unwrap(), 1.0);
+    assert_eq!(extract(METRICS, r#"node_filesystem_avail_bytes{mountpoint="/"}"#, None).unwrap(), 1000.0);
+    assert!(extract(METRICS, "node_filesystem_avail_bytes", None).is_err());
+    assert!(extract(METRICS, "missing_metric", None).is_err());
+  }
+
+  #[test]
+  fn test_extract_aggregated() {
+    let selector = r#"node_filesystem_avail_bytes{fstype!="tmpfs"}"#;
+    assert_eq!(extract(METRICS, selector, Some("sum")).unwrap(), 4000.0);
+    assert_eq!(extract(METRICS, selector, Some("avg")).unwrap(), 2000.0);
+    assert_eq!(extract(METRICS, "node_filesystem_avail_bytes", Some("min")).unwrap(), 500.0);
+    assert_eq!(extract(METRICS, r#"node_filesystem_avail_bytes{mountpoint=~"/d.*|/run"}"#, Some("max")).unwrap(), 3000.0);
+    assert_eq!(extract(METRICS, r#"node_filesystem_avail_bytes{device!~"/dev/.*"}"#, Some("count")).unwrap(), 1.0);
+    assert_eq!(extract(METRICS, "missing_metric", Some("count")).unwrap(), 0.0);
+    assert!(extract(METRICS, "missing_metric", Some("avg")).is_err());
+  }
+}
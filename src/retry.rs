@@ -0,0 +1,169 @@
+use std::{fmt, thread, time::{Duration, Instant}};
+
+use anyhow::{Error, Result};
+
+use crate::yamlworkflow::RetryPolicy;
+
+/// Kinds of errors that retry policies can single out, as they are usually transient
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+  Http5xx,
+  Http4xx,
+  Timeout,
+  Connection,
+  Io,
+}
+
+const KINDS: [ErrorKind; 5] = [ErrorKind::Http5xx, ErrorKind::Http4xx, ErrorKind::Timeout, ErrorKind::Connection, ErrorKind::Io];
+
+impl ErrorKind {
+  fn name(&self) -> &'static str {
+    match self {
+      ErrorKind::Http5xx => "http_5xx",
+      ErrorKind::Http4xx => "http_4xx",
+      ErrorKind::Timeout => "timeout",
+      ErrorKind::Connection => "connection",
+      ErrorKind::Io => "io",
+    }
+  }
+}
+
+/// Whether retry policies know a kind of errors by this name, e.g. `http_5xx`
+pub fn is_kind(name: &str) -> bool {
+  KINDS.iter().any(|kind| kind.name() == name)
+}
+
+/// An error of a known kind
+#[derive(Debug)]
+struct KindError {
+  kind: ErrorKind,
+  message: String,
+}
+
+impl fmt::Display for KindError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for KindError {}
+
+/// Creates an error of a known kind
+pub fn error(kind: ErrorKind, message: String) -> Error {
+  Error::new(KindError { kind, message })
+}
+
+/// The kind of an error, looking through the errors it wraps
+pub fn kind_of(err: &Error) -> Option<ErrorKind> {
+  err.chain().find_map(|e| e.downcast_ref::<KindError>()).map(|e| e.kind)
+}
+
+fn is_retryable(policy: &RetryPolicy, err: &Error) -> bool {
+  policy.retry_on.is_empty() || kind_of(err).is_some_and(|kind| policy.retry_on.iter().any(|r| r == kind.name()))
+}
+
+/// Wait before a given retry (1 for the first one) in seconds, without jitter. It can be too long for a `Duration`.
+fn backoff(policy: &RetryPolicy, retry: u32) -> f64 {
+  let wait = policy.interval.as_secs_f64() * policy.backoff.powi(retry.saturating_sub(1) as i32);
+  policy.max_interval.map_or(wait, |max| wait.min(max.as_secs_f64()))
+}
+
+fn with_jitter(wait: f64, jitter: f64) -> f64 {
+  wait * (1.0 - jitter.clamp(0.0, 1.0) * fastrand::f64())
+}
+
+/// Wait before a given retry, with jitter, `Duration::MAX` if it is too long to tell
+fn wait_before(policy: &RetryPolicy, retry: u32) -> Duration {
+  Duration::try_from_secs_f64(with_jitter(backoff(policy, retry), policy.jitter).max(0.0)).unwrap_or(Duration::MAX)
+}
+
+/// Runs an operation until it succeeds, following a retry policy: up to `max_attempts` attempts, waiting `interval`
+/// before the first retry and `backoff` times longer before each next one, at most `max_interval` and minus a random
+/// share (`jitter`). Errors whose kind is not in `retry_on`, or a wait that would go past `max_total_time`, stop
+/// retries early. The last error is returned when all attempts fail.
+pub fn retry<T>(policy: &RetryPolicy, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+  let start = Instant::now();
+  let mut attempt = 1;
+  loop {
+    let err = match operation() {
+      Ok(result) => return Ok(result),
+      Err(err) => err
+    };
+    if attempt >= policy.max_attempts || !is_retryable(policy, &err) {
+      return Err(err);
+    }
+    let wait = wait_before(policy, attempt);
+    if let Some(max) = policy.max_total_time {
+      if start.elapsed().checked_add(wait).is_none_or(|total| total > max) {
+        log::debug!("{}, giving up as retrying would take more than {} seconds", err, max.as_secs_f64());
+        return Err(err);
+      }
+    }
+    log::debug!("{}, retrying in {:.1} seconds ({} attempt(s) left)", err, wait.as_secs_f64(), policy.max_attempts - attempt);
+    thread::sleep(wait);
+    attempt += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::anyhow;
+
+  fn policy(max_attempts: u32, retry_on: &[&str]) -> RetryPolicy {
+    RetryPolicy { max_attempts, retry_on: retry_on.iter().map(|r| r.to_string()).collect(), ..RetryPolicy::fixed(0, Duration::ZERO) }
+  }
+
+  #[test]
+  fn test_attempts() {
+    let mut calls = 0;
+    let outcome = retry(&policy(3, &[]), || { calls += 1; if calls < 3 { Err(anyhow!("not yet")) } else { Ok(calls) } });
+    assert_eq!(outcome.unwrap(), 3);
+
+    let mut calls = 0;
+    let outcome: Result<()> = retry(&policy(2, &[]), || { calls += 1; Err(anyhow!("failure {}", calls)) });
+    assert_eq!(outcome.unwrap_err().to_string(), "failure 2");
+    assert!(is_kind("http_5xx") && !is_kind("http_6xx"));
+  }
+
+  #[test]
+  fn test_retry_on_kinds() {
+    let mut calls = 0;
+    let outcome: Result<()> = retry(&policy(5, &["http_5xx", "timeout"]), || {
+      calls += 1;
+      if calls < 3 { Err(error(ErrorKind::Http5xx, "bad gateway".to_string())) } else { Err(anyhow!("unparseable body")) }
+    });
+    assert!(outcome.is_err());
+    assert_eq!(calls, 3);
+
+    let wrapped = error(ErrorKind::Timeout, "timed out".to_string()).context("while fetching");
+    assert_eq!(kind_of(&wrapped), Some(ErrorKind::Timeout));
+    assert_eq!(kind_of(&anyhow!("plain")), None);
+  }
+
+  #[test]
+  fn test_backoff() {
+    let policy = RetryPolicy { backoff: 2.0, max_interval: Some(Duration::from_secs(5)), ..RetryPolicy::fixed(4, Duration::from_secs(1)) };
+    let waits: Vec<f64> = (1..=4).map(|retry| backoff(&policy, retry)).collect();
+    assert_eq!(waits, vec![1.0, 2.0, 4.0, 5.0]);
+    let jittered = with_jitter(10.0, 0.5);
+    assert!((5.0..=10.0).contains(&jittered));
+
+    // a schedule too long for a Duration gives up on the total time instead of panicking
+    let policy = RetryPolicy { backoff: 10.0, max_total_time: Some(Duration::from_secs(60)), ..RetryPolicy::fixed(24, Duration::from_secs(1)) };
+    assert_eq!(wait_before(&policy, 24), Duration::MAX);
+    let mut calls = 0;
+    let outcome: Result<()> = retry(&RetryPolicy { interval: Duration::from_secs(u64::MAX), jitter: 0.5, ..policy }, || { calls += 1; Err(anyhow!("down")) });
+    assert!(outcome.is_err());
+    assert_eq!(calls, 1);
+  }
+
+  #[test]
+  fn test_max_total_time() {
+    let policy = RetryPolicy { max_total_time: Some(Duration::from_millis(50)), ..RetryPolicy::fixed(10, Duration::from_millis(40)) };
+    let mut calls = 0;
+    let outcome: Result<()> = retry(&policy, || { calls += 1; Err(anyhow!("down")) });
+    assert!(outcome.is_err());
+    assert_eq!(calls, 2);
+  }
+}
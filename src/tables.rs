@@ -0,0 +1,147 @@
+use anyhow::{Result, anyhow, bail};
+
+use crate::{compare, yamlworkflow::RowFilter};
+
+/// A CSV (or TSV...) table held in memory, with an optional header row
+#[derive(Debug)]
+pub struct Table {
+  headers: Option<Vec<String>>,
+  rows: Vec<Vec<String>>,
+  delimiter: u8,
+}
+
+/// Guesses whether the first row is a header: all its fields are non-empty, non-numeric and distinct
+fn looks_like_header(row: &[String]) -> bool {
+  row.iter().all(|f| !f.trim().is_empty() && f.trim().parse::<f64>().is_err())
+    && row.iter().enumerate().all(|(i, f)| !row[..i].contains(f))
+}
+
+impl Table {
+  /// Parses a delimited text; headers are detected when `has_headers` is not given
+  pub fn parse(input: &str, delimiter: char, has_headers: Option<bool>) -> Result<Self> {
+    if !delimiter.is_ascii() { bail!("CSV delimiter must be an ASCII character"); }
+    let delimiter = delimiter as u8;
+    let mut reader = csv::ReaderBuilder::new()
+      .delimiter(delimiter)
+      .has_headers(false)
+      .flexible(true)
+      .from_reader(input.as_bytes());
+    let mut rows = vec![];
+    for record in reader.records() {
+      let record = record.map_err(|e| anyhow!("could not parse CSV input: {}", e))?;
+      rows.push(record.iter().map(str::to_string).collect::<Vec<String>>());
+    }
+    let headers = match (has_headers, rows.first()) {
+      (Some(true), Some(_)) => Some(rows.remove(0)),
+      (None, Some(first)) if looks_like_header(first) => Some(rows.remove(0)),
+      _ => None
+    };
+    Ok(Self { headers, rows, delimiter })
+  }
+
+  /// Finds a column by header name, or by its position (starting at 0)
+  fn column_index(&self, column: &str) -> Result<usize> {
+    self.headers.as_ref().and_then(|h| h.iter().position(|name| name == column))
+      .or_else(|| column.parse().ok())
+      .ok_or(anyhow!("no CSV column named {}", column))
+  }
+
+  fn cell(row: &[String], index: usize) -> &str {
+    row.get(index).map(|s| s.as_str()).unwrap_or("")
+  }
+
+  /// Keeps only the rows meeting all the given conditions
+  pub fn filter(&mut self, filters: &[RowFilter]) -> Result<()> {
+    let filters = filters.iter()
+      .map(|f| Ok((self.column_index(&f.column)?, f)))
+      .collect::<Result<Vec<_>>>()?;
+    let mut kept = vec![];
+    for row in self.rows.drain(..) {
+      let mut keep = true;
+      for (index, f) in &filters {
+        keep = keep && compare::compare(Self::cell(&row, *index), &f.compare_for, &f.compare_with)?;
+      }
+      if keep { kept.push(row); }
+    }
+    self.rows = kept;
+    Ok(())
+  }
+
+  /// Writes the rows back as delimited text, limited to some columns if any are given
+  pub fn select(&self, columns: &[String]) -> Result<String> {
+    let indexes = match columns.is_empty() {
+      true => (0..self.headers.as_ref().or(self.rows.first()).map(|r| r.len()).unwrap_or(0)).collect(),
+      false => columns.iter().map(|c| self.column_index(c)).collect::<Result<Vec<usize>>>()?
+    };
+    let mut writer = csv::WriterBuilder::new().delimiter(self.delimiter).flexible(true).from_writer(vec![]);
+    for row in self.headers.iter().chain(self.rows.iter()) {
+      writer.write_record(indexes.iter().map(|i| Self::cell(row, *i)))
+        .map_err(|e| anyhow!("could not write CSV output: {}", e))?;
+    }
+    let bytes = writer.into_inner().map_err(|e| anyhow!("could not write CSV output: {}", e))?;
+    String::from_utf8(bytes).map_err(|_| anyhow!("CSV output is not valid UTF-8"))
+  }
+
+  /// Computes count, sum, avg, min, max, first, last or list (as JSON) over a column
+  pub fn aggregate(&self, column: Option<&str>, aggregate: &str) -> Result<String> {
+    if aggregate == "count" { return Ok(self.rows.len().to_string()); }
+    let index = self.column_index(column.ok_or(anyhow!("aggregation {} needs a column", aggregate))?)?;
+    let values: Vec<&str> = self.rows.iter().map(|r| Self::cell(r, index)).collect();
+    let numbers = || values.iter()
+      .map(|v| v.trim().parse::<f64>().map_err(|_| anyhow!("value is not numeric: {}", v)))
+      .collect::<Result<Vec<f64>>>();
+    let not_empty = || if values.is_empty() { Err(anyhow!("no CSV rows left to compute {}", aggregate)) } else { Ok(()) };
+    Ok(match aggregate {
+      "sum" => numbers()?.iter().sum::<f64>().to_string(),
+      "avg" => { not_empty()?; (numbers()?.iter().sum::<f64>() / values.len() as f64).to_string() },
+      "min" => { not_empty()?; numbers()?.into_iter().fold(f64::INFINITY, f64::min).to_string() },
+      "max" => { not_empty()?; numbers()?.into_iter().fold(f64::NEG_INFINITY, f64::max).to_string() },
+      "first" => { not_empty()?; values[0].to_string() },
+      "last" => { not_empty()?; values[values.len() - 1].to_string() },
+      "list" => serde_json::to_string(&values)?,
+      _ => bail!("unsupported aggregation: {}", aggregate)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static DUMP: &str = "name,state,temp\nsensor 1,up,41.5\n\"sensor, 2\",down,45\nsensor 3,up,39\n";
+
+  fn filter(column: &str, compare_for: &str, compare_with: &str) -> RowFilter {
+    RowFilter { column: column.to_string(), compare_for: compare_for.to_string(), compare_with: compare_with.to_string() }
+  }
+
+  #[test]
+  fn test_parse_and_aggregate() {
+    let table = Table::parse(DUMP, ',', None).unwrap();
+    assert_eq!(table.aggregate(None, "count").unwrap(), "3");
+    assert_eq!(table.aggregate(Some("temp"), "sum").unwrap(), "125.5");
+    assert_eq!(table.aggregate(Some("temp"), "max").unwrap(), "45");
+    assert_eq!(table.aggregate(Some("2"), "min").unwrap(), "39");
+    assert_eq!(table.aggregate(Some("name"), "list").unwrap(), r#"["sensor 1","sensor, 2","sensor 3"]"#);
+    assert!(table.aggregate(Some("name"), "sum").is_err());
+    assert!(table.aggregate(Some("missing"), "first").is_err());
+  }
+
+  #[test]
+  fn test_header_detection() {
+    let table = Table::parse("a\t1\nb\t2\n", '\t', None).unwrap();
+    assert_eq!(table.aggregate(Some("1"), "sum").unwrap(), "3");
+    let table = Table::parse("a\t1\nb\t2\n", '\t', Some(true)).unwrap();
+    assert_eq!(table.aggregate(None, "count").unwrap(), "1");
+  }
+
+  #[test]
+  fn test_filter_and_select() {
+    let mut table = Table::parse(DUMP, ',', Some(true)).unwrap();
+    table.filter(&[filter("state", "equality", "up"), filter("temp", "gt", "40")]).unwrap();
+    assert_eq!(table.select(&[]).unwrap(), "name,state,temp\nsensor 1,up,41.5\n");
+
+    let mut table = Table::parse(DUMP, ',', Some(true)).unwrap();
+    table.filter(&[filter("state", "equality", "down")]).unwrap();
+    assert_eq!(table.select(&["name".to_string()]).unwrap(), "name\n\"sensor, 2\"\n");
+  }
+}
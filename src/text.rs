@@ -0,0 +1,188 @@
+use regex::{Captures, Regex};
+use serde_json::Value;
+use anyhow::{Result, anyhow, bail};
+
+use crate::yamlworkflow::{TextOperation, PadSide};
+
+/// The outcome of a regex extraction: the main value (first capture group, or the whole match
+/// without groups), one value per named group, and the number of matches found in the input.
+/// With `all_matches`, values are JSON arrays holding one entry per match.
+#[derive(Debug, PartialEq)]
+pub struct Extraction {
+  pub count: usize,
+  pub output: String,
+  pub named: Vec<(String, String)>,
+}
+
+fn main_value(captures: &Captures) -> String {
+  captures.get(1).or(captures.get(0)).map(|m| m.as_str().to_string()).unwrap_or_default()
+}
+
+fn named_value(captures: &Captures, name: &str) -> String {
+  captures.name(name).map(|m| m.as_str().to_string()).unwrap_or_default()
+}
+
+/// Stores a list of values as a JSON list, the way list variables are kept in the state
+pub fn to_list(values: Vec<String>) -> String {
+  Value::Array(values.into_iter().map(Value::String).collect()).to_string()
+}
+
+pub fn extract(input: &str, regex: &str, all_matches: bool) -> Result<Extraction> {
+  let re = compile(regex)?;
+  let names: Vec<&str> = re.capture_names().flatten().collect();
+  let matches: Vec<Captures> = re.captures_iter(input).collect();
+
+  if all_matches {
+    return Ok(Extraction {
+      count: matches.len(),
+      output: to_list(matches.iter().map(main_value).collect()),
+      named: names.iter()
+        .map(|name| (name.to_string(), to_list(matches.iter().map(|c| named_value(c, name)).collect())))
+        .collect(),
+    });
+  }
+
+  let Some(first) = matches.first() else { bail!("text not found in text extractor") };
+  Ok(Extraction {
+    count: matches.len(),
+    output: main_value(first),
+    named: names.iter().map(|name| (name.to_string(), named_value(first, name))).collect(),
+  })
+}
+
+fn compile(regex: &str) -> Result<Regex> {
+  Regex::new(regex).map_err(|_| anyhow!("error creating regex from input: {}", regex))
+}
+
+/// Parses a JSON list of values as produced by list-making steps; non-string items keep their JSON form
+pub fn parse_list(input: &str) -> Result<Vec<String>> {
+  match serde_json::from_str(input) {
+    Ok(Value::Array(items)) => Ok(items.iter().map(crate::json::value_to_string).collect()),
+    _ => bail!("input is not a JSON list: {}", input)
+  }
+}
+
+/// Reads a list of items out of a text, depending on `format`: `json` (list), `lines` (non-empty lines),
+/// `comma` (comma-separated, trimmed), or `regex` (all matches of `regex`, first capture group if any).
+/// Without a format, JSON lists are detected and anything else is read as lines.
+pub fn split_items(input: &str, format: Option<&str>, regex: Option<&str>) -> Result<Vec<String>> {
+  let lines = || input.lines().filter(|l| !l.trim().is_empty()).map(str::to_string).collect();
+  match (format, regex) {
+    (Some("regex"), Some(regex)) | (None, Some(regex)) => parse_list(&extract(input, regex, true)?.output),
+    (Some("regex"), None) => bail!("the regex format needs a regex"),
+    (Some("json"), _) => parse_list(input),
+    (Some("lines"), _) => Ok(lines()),
+    (Some("comma"), _) => Ok(input.split(',').map(|i| i.trim().to_string()).filter(|i| !i.is_empty()).collect()),
+    (Some(other), _) => bail!("unsupported list format: {}", other),
+    (None, None) => Ok(parse_list(input).unwrap_or_else(|_| lines()))
+  }
+}
+
+fn apply(input: String, operation: &TextOperation) -> Result<String> {
+  Ok(match operation {
+    TextOperation::Trim => input.trim().to_string(),
+    TextOperation::TrimStart => input.trim_start().to_string(),
+    TextOperation::TrimEnd => input.trim_end().to_string(),
+    TextOperation::Uppercase => input.to_uppercase(),
+    TextOperation::Lowercase => input.to_lowercase(),
+    TextOperation::Replace { regex, with } => compile(regex)?.replace_all(&input, with.as_str()).to_string(),
+    TextOperation::Split(separator) => to_list(input.split(separator.as_str()).map(str::to_string).collect()),
+    TextOperation::Join(separator) => parse_list(&input)?.join(separator),
+    TextOperation::Substring { start, length } => {
+      let chars = input.chars().skip(*start);
+      match length {
+        Some(length) => chars.take(*length).collect(),
+        None => chars.collect()
+      }
+    },
+    TextOperation::Pad { width, with, side } => {
+      let padding: String = std::iter::repeat_n(*with, width.saturating_sub(input.chars().count())).collect();
+      if *side == PadSide::Left { padding + &input } else { input + &padding }
+    },
+    TextOperation::FilterLines { regex, invert } => {
+      let re = compile(regex)?;
+      input.lines().filter(|line| re.is_match(line) != *invert).collect::<Vec<_>>().join("\n")
+    },
+    TextOperation::Truncate { length, ellipsis } => {
+      if input.chars().count() <= *length { input }
+      else {
+        let kept = length.saturating_sub(ellipsis.chars().count());
+        input.chars().take(kept).collect::<String>() + ellipsis
+      }
+    },
+  })
+}
+
+/// Runs a text through a pipeline of operations
+pub fn transform(input: &str, operations: &[TextOperation]) -> Result<String> {
+  operations.iter().try_fold(input.to_string(), apply)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static PAGE: &str = "disk=sda temp=41 fan=1200\ndisk=sdb temp=45 fan=1300\n";
+
+  #[test]
+  fn test_extract_named_groups() {
+    let e = extract(PAGE, r"disk=(?P<disk>\w+) temp=(?P<temp>\d+)", false).unwrap();
+    assert_eq!(e.count, 2);
+    assert_eq!(e.output, "sda");
+    assert_eq!(e.named, vec![("disk".to_string(), "sda".to_string()), ("temp".to_string(), "41".to_string())]);
+  }
+
+  #[test]
+  fn test_extract_all_matches() {
+    let e = extract(PAGE, r"temp=(?P<temp>\d+)", true).unwrap();
+    assert_eq!(e.count, 2);
+    assert_eq!(e.output, r#"["41","45"]"#);
+    assert_eq!(e.named[0].1, r#"["41","45"]"#);
+
+    let e = extract(PAGE, r"fan=\d+", true).unwrap();
+    assert_eq!(e.output, r#"["fan=1200","fan=1300"]"#);
+  }
+
+  #[test]
+  fn test_extract_no_match() {
+    assert!(extract(PAGE, r"error", false).is_err());
+    let e = extract(PAGE, r"error", true).unwrap();
+    assert_eq!((e.count, e.output.as_str()), (0, "[]"));
+  }
+
+  #[test]
+  fn test_split_items() {
+    assert_eq!(split_items("a\n\nb\n", None, None).unwrap(), vec!["a", "b"]);
+    assert_eq!(split_items(r#"["a", 2]"#, None, None).unwrap(), vec!["a", "2"]);
+    assert_eq!(split_items("a, b,,c", Some("comma"), None).unwrap(), vec!["a", "b", "c"]);
+    assert_eq!(split_items(PAGE, None, Some(r"disk=(\w+)")).unwrap(), vec!["sda", "sdb"]);
+    assert_eq!(split_items(r#"["a"]"#, Some("lines"), None).unwrap(), vec![r#"["a"]"#]);
+    assert!(split_items("a,b", Some("json"), None).is_err());
+    assert!(split_items("a,b", Some("regex"), None).is_err());
+  }
+
+  fn ops(yaml: &str) -> Vec<TextOperation> {
+    serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(yaml)).unwrap()
+  }
+
+  #[test]
+  fn test_transform_basic_operations() {
+    assert_eq!(transform("  Hello World\n", &ops("[trim, uppercase]")).unwrap(), "HELLO WORLD");
+    assert_eq!(transform("a1b22c", &ops(r#"[replace: {regex: '(\d+)', with: '<$1>'}]"#)).unwrap(), "a<1>b<22>c");
+    assert_eq!(transform("abcdef", &ops("[substring: {start: 1, length: 3}]")).unwrap(), "bcd");
+    assert_eq!(transform("42", &ops("[pad: {width: 5, with: '0'}]")).unwrap(), "00042");
+    assert_eq!(transform("42", &ops("[pad: {width: 4, side: right}]")).unwrap(), "42  ");
+    assert_eq!(transform("abcdefgh", &ops("[truncate: {length: 5, ellipsis: '...'}]")).unwrap(), "ab...");
+    assert_eq!(transform("abc", &ops("[truncate: {length: 5}]")).unwrap(), "abc");
+  }
+
+  #[test]
+  fn test_transform_lists_and_lines() {
+    assert_eq!(transform("a, b,c", &ops("[split: ',']")).unwrap(), r#"["a"," b","c"]"#);
+    assert_eq!(transform("a, b,c", &ops("[split: ',', join: ';']")).unwrap(), "a; b;c");
+    assert!(transform("a,b", &ops("[join: ';']")).is_err());
+    let log = "INFO start\nERROR disk\nINFO end\nERROR fan";
+    assert_eq!(transform(log, &ops("[filter_lines: {regex: ^ERROR}]")).unwrap(), "ERROR disk\nERROR fan");
+    assert_eq!(transform(log, &ops("[filter_lines: {regex: ^ERROR, invert: true}]")).unwrap(), "INFO start\nINFO end");
+  }
+}
@@ -1,16 +1,18 @@
 use regex::Regex;
 
-use crate::{triggers::{self, Trigger}, notifiers, data, files };
+use crate::{triggers::{self, Trigger}, notifiers, compare, data, datetime, diff, encode, expr, files, json, markup, persist, prometheus, retry, tables, text };
 use super::yamlworkflow as yw; 
 
-use std::{collections::HashMap, sync::Arc, thread, time::Duration };
+use std::{collections::HashMap, fmt, sync::{Arc, OnceLock, atomic::{AtomicBool, Ordering}, mpsc}, thread, time::{Duration, Instant} };
 use anyhow::{anyhow,Error, bail };
 
-#[derive(Debug,Default)]
+#[derive(Debug,Default,Clone)]
 /// A state structure for the workflow to store variables
 struct State {
     last_output: Option<String>,
     vars: HashMap<String, String>,
+    /// type of the step being run
+    current_step: &'static str,
 }
 
 impl State {
@@ -29,152 +31,1002 @@ impl State {
         }
         else {self.last_output.as_ref().ok_or(anyhow!("no input available for text extraction")) }
     }
+    /// Replaces `{{ var_name }}` placeholders in a text with the value of the variables; unknown ones are left as-is
+    fn render(&self, template: &str) -> String {
+        static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+        let re = PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap());
+        re.replace_all(template, |captures: &regex::Captures| {
+            self.vars.get(&captures[1]).cloned().unwrap_or_else(|| captures[0].to_string())
+        }).to_string()
+    }
+    /// Exposes an error to the steps handling it, as the `error` and `error_step` variables
+    fn set_error(&mut self, err: &Error) {
+        self.vars.insert("error".to_string(), err.to_string());
+        let step = err.downcast_ref::<StepFailure>().map(|f| f.step).unwrap_or(self.current_step);
+        self.vars.insert("error_step".to_string(), step.to_string());
+    }
+}
+
+/// An error tagged with the type of the step it comes from
+#[derive(Debug)]
+struct StepFailure {
+    step: &'static str,
+    err: Error,
+}
+
+impl StepFailure {
+    /// Tags an error with a step, unless it already comes from a nested step
+    fn tag(err: Error, step: &yw::Step) -> Error {
+        if err.is::<StepFailure>() || err.is::<Signal>() { err } else { Error::new(StepFailure { step: step.kind(), err }) }
+    }
+}
+
+/// Control flow requested by `end_flow`, `break` and `continue` steps, or by a parallel block that no longer
+/// needs a branch. These travel up the steps like errors, until the loop, flow or branch they are meant for,
+/// but are never handled as failures.
+#[derive(Debug)]
+enum Signal {
+    EndFlow,
+    Break,
+    Continue,
+    Cancelled,
+}
+
+impl Signal {
+    fn of(err: &Error) -> Option<&Signal> {
+        err.downcast_ref::<Signal>()
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Signal::EndFlow => write!(f, "flow ended"),
+            Signal::Break => write!(f, "break step used outside of a loop"),
+            Signal::Continue => write!(f, "continue step used outside of a loop"),
+            Signal::Cancelled => write!(f, "branch cancelled, another one succeeded first"),
+        }
+    }
+}
+
+impl std::error::Error for Signal {}
+
+impl fmt::Display for StepFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.err)
+    }
+}
+
+impl std::error::Error for StepFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.err.as_ref())
+    }
+}
+
+#[derive(Clone)]
+/// What steps need from their flow, besides the state of the current run
+struct Context {
+    flow_name: String,
+    notifiers: Arc<HashMap<String, Box<dyn notifiers::Notifier>>>,
+    http: data::HttpSession,
+    store: Arc<persist::Store>,
+    /// set when the parallel blocks this runs in no longer need its result
+    cancelled: Vec<Arc<AtomicBool>>,
+    functions: Arc<HashMap<String, yw::Function>>,
+    /// number of nested function calls
+    call_depth: usize,
+    /// when the running step (or the flow) must be done by
+    deadline: Option<Deadline>,
+    /// when the failure steps of the running step must be done by, which is not limited by its own timeout
+    handler_deadline: Option<Deadline>,
+}
+
+#[derive(Clone)]
+struct Deadline {
+    at: Instant,
+    /// what is logged and reported once it has passed
+    reason: String,
+}
+
+impl Deadline {
+    fn after(duration: Duration, reason: String) -> Self {
+        Self { at: Instant::now() + duration, reason }
+    }
+}
+
+impl Context {
+    /// Fails once the deadline has passed
+    fn check_deadline(&self) -> Result<(), Error> {
+        match &self.deadline {
+            Some(deadline) if Instant::now() >= deadline.at => {
+                log::debug!("[{}] {}", self.flow_name, deadline.reason);
+                Err(retry::error(retry::ErrorKind::Timeout, deadline.reason.clone()))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Whether the parallel blocks this runs in no longer need its result
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.iter().any(|c| c.load(Ordering::Relaxed))
+    }
+
+    /// Sleeps, but not past the deadline. Sleeping stops early once cancelled, and the next step then stops the branch.
+    fn sleep(&self, duration: Duration) -> Result<(), Error> {
+        let end = Instant::now() + duration;
+        let end = match &self.deadline {
+            Some(deadline) if deadline.at < end => deadline.at,
+            _ => end
+        };
+        loop {
+            let remaining = end.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.is_cancelled() { break; }
+            thread::sleep(remaining.min(CANCEL_CHECK_INTERVAL));
+        }
+        self.check_deadline()
+    }
+
+    /// Sends a message through a notifier, giving up at the deadline. Notifiers may wait forever on a hung
+    /// connection, so with a deadline the message goes from a helper thread, which is left behind if it hangs.
+    fn send_message(&self, notifier: &str, message: &str) -> Result<(), Error> {
+        let Some(deadline) = &self.deadline else {
+            return self.notifiers[notifier].send_message(message);
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (notifiers, name, message) = (self.notifiers.clone(), notifier.to_string(), message.to_string());
+        thread::spawn(move || {
+            let _ = sender.send(notifiers[&name].send_message(&message));
+        });
+        match receiver.recv_timeout(deadline.at.saturating_duration_since(Instant::now())) {
+            Ok(outcome) => outcome,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(retry::error(retry::ErrorKind::Timeout, deadline.reason.clone())),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(anyhow!("notifier {} stopped without sending the message", notifier)),
+        }
+    }
+
+    /// The retry policy of a step, without retries past the deadline
+    fn retry_policy(&self, fail_spec: &yw::FailSpec) -> yw::RetryPolicy {
+        self.within_deadline(fail_spec.retry_policy())
+    }
+
+    fn within_deadline(&self, mut policy: yw::RetryPolicy) -> yw::RetryPolicy {
+        if let Some(deadline) = &self.deadline {
+            let remaining = deadline.at.saturating_duration_since(Instant::now());
+            policy.max_total_time = Some(policy.max_total_time.map_or(remaining, |max| max.min(remaining)));
+        }
+        policy
+    }
+
+    /// The context to run a step in, if it differs from this one: the step's timeout applies to the step,
+    /// while its failure steps are only bound by the deadlines around it
+    fn for_step(&self, step: &yw::Step) -> Option<Context> {
+        let timeout = step.timeout();
+        let at = |deadline: &Option<Deadline>| deadline.as_ref().map(|d| d.at);
+        if timeout.is_none() && at(&self.deadline) == at(&self.handler_deadline) {
+            return None;
+        }
+        let mut step_ctx = self.clone();
+        step_ctx.handler_deadline = self.deadline.clone();
+        if let Some(timeout) = timeout {
+            let deadline = Deadline::after(timeout, format!("step {} timed out after {:?}", step.kind(), timeout));
+            if at(&self.deadline).is_none_or(|at| deadline.at < at) {
+                step_ctx.deadline = Some(deadline);
+            }
+        }
+        Some(step_ctx)
+    }
 }
 
+/// How often sleeping branches check whether they were cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How deep functions may call each other (or themselves), to stop runaway recursion
+const MAX_CALL_DEPTH: usize = 32;
+
 fn handle_failure_with_err(
     err: Error,
-    notifiers: & HashMap<String, Box<dyn notifiers::Notifier>>,
-    steps: & Vec<yw::Step>,
+    ctx: &Context,
+    steps: &[yw::Step],
     state: & mut State,
-    flow_name: &str
 ) -> Result<(), Error> {
-    log::warn!("{}",err.to_string());
+    let flow_name = ctx.flow_name.as_str();
+    if Signal::of(&err).is_some() {
+        return Err(err);
+    }
+    // a step cut short by its deadline may fail with some other error first, e.g. from an HTTP request
+    let err = match &ctx.deadline {
+        Some(deadline) if Instant::now() >= deadline.at && !err.to_string().contains(&deadline.reason) => {
+            err.context(deadline.reason.clone())
+        },
+        _ => err
+    };
+    // errors are logged where they are handled, or by the flow once they reach it, not at every level they go through
     if !steps.is_empty() {
+        log::warn!("[{}] {}", flow_name, err);
         log::debug!("[{}] entering a failure sub-flow", flow_name);
-        run_steps(notifiers, steps, state, flow_name)?; // if this fails too, we give up and shoot up the error
+        state.set_error(&err);
+        let mut handler_ctx = ctx.clone();
+        handler_ctx.deadline = ctx.handler_deadline.clone();
+        run_steps(&handler_ctx, steps, state)?; // if this fails too, we give up and shoot up the error
         log::debug!("[{}] Failure sub-flow completed, resuming main flow", flow_name);
         return Ok(());
     }
     Err(err) // no error handling in workflow, abort flow with error
 }
 
+/// A flow stopped by an `end_flow` step completed successfully
+fn ended_early_is_ok(outcome: Result<(), Error>) -> Result<(), Error> {
+    match outcome {
+        Err(err) if matches!(Signal::of(&err), Some(Signal::EndFlow)) => Ok(()),
+        other => other
+    }
+}
+
+/// Runs the steps of one loop iteration, telling whether the loop should go on (false after a `break` step)
+fn run_iteration(
+    ctx: &Context,
+    steps: &[yw::Step],
+    state: &mut State,
+) -> Result<bool, Error> {
+    match run_steps(ctx, steps, state) {
+        Ok(()) => Ok(true),
+        Err(err) => match Signal::of(&err) {
+            Some(Signal::Break) => Ok(false),
+            Some(Signal::Continue) => Ok(true),
+            _ => Err(err)
+        }
+    }
+}
+
+/// Runs the steps of a `while` (condition checked before each iteration) or `repeat_until` loop
+/// (condition checked after each iteration) until the condition says to stop or iterations run out
+fn run_loop(
+    ctx: &Context,
+    spec: &yw::LoopSpec,
+    state: &mut State,
+    check_first: bool,
+) -> Result<(), Error> {
+    let flow_name = ctx.flow_name.as_str();
+    let condition = expr::Expression::parse(&spec.condition)?;
+    // while loops go on as long as the condition holds, repeat_until loops until it does
+    let should_stop = |state: &State| -> Result<bool, Error> {
+        Ok(condition.evaluate(&state.vars)?.as_bool()? != check_first)
+    };
+
+    for iteration in 1..=spec.max_iterations {
+        if check_first && should_stop(state)? {
+            log::debug!("[{}] loop condition no longer met after {} iteration(s)", flow_name, iteration - 1);
+            return Ok(());
+        }
+        if iteration > 1 {
+            if let Some(delay) = spec.delay { ctx.sleep(delay)?; }
+        }
+        if let Some(iteration_var) = &spec.iteration_var {
+            state.vars.insert(iteration_var.clone(), iteration.to_string());
+        }
+        if !run_iteration(ctx, &spec.steps, state)? {
+            log::debug!("[{}] loop stopped by a break step after {} iteration(s)", flow_name, iteration);
+            return Ok(());
+        }
+        if !check_first && should_stop(state)? {
+            log::debug!("[{}] loop condition met after {} iteration(s)", flow_name, iteration);
+            return Ok(());
+        }
+    }
+    // the last allowed iteration may have been the one that made the condition false
+    if check_first && should_stop(state)? {
+        log::debug!("[{}] loop condition no longer met after {} iteration(s)", flow_name, spec.max_iterations);
+        return Ok(());
+    }
+
+    if spec.on_exhausted.is_empty() {
+        bail!("loop gave up after {} iterations: {}", spec.max_iterations, spec.condition);
+    }
+    log::debug!("[{}] loop exhausted after {} iterations, entering a sub-flow", flow_name, spec.max_iterations);
+    run_steps(ctx, &spec.on_exhausted, state)
+}
+
+/// Runs the probe steps, then checks the condition, until it holds. A failing probe or a condition that
+/// cannot be evaluated yet (e.g. a variable the probe did not set) just means it does not hold yet.
+fn wait_until(
+    ctx: &Context,
+    condition: &str,
+    probe: &[yw::Step],
+    interval: Duration,
+    timeout: Option<Duration>,
+    state: &mut State,
+) -> Result<(), Error> {
+    let flow_name = ctx.flow_name.as_str();
+    if timeout.is_none() { bail!("wait_until needs a timeout"); }
+    let parsed = expr::Expression::parse(condition)?;
+    let mut poll = 0;
+    loop {
+        poll += 1;
+        let outcome = run_steps(ctx, probe, state)
+            .and_then(|()| parsed.evaluate(&state.vars)?.as_bool());
+        let reason = match outcome {
+            Ok(true) => {
+                log::info!("[{}] condition {} met after {} poll(s)", flow_name, condition, poll);
+                return Ok(());
+            },
+            Ok(false) => "condition is false".to_string(),
+            Err(err) if Signal::of(&err).is_some() => return Err(err),
+            Err(err) => err.to_string(),
+        };
+        log::debug!("[{}] condition {} not met yet ({}), polling again in {:?}", flow_name, condition, reason, interval);
+        ctx.sleep(interval).map_err(|err| anyhow!("{}, condition {} still not met: {}", err, condition, reason))?;
+    }
+}
+
+struct BranchOutcome {
+    index: usize,
+    name: String,
+    succeeded: bool,
+    state: State,
+}
+
+/// Runs branches of steps on their own threads, each with a copy of the state, then merges the variables
+/// they set back into the state. `join` says which branches must succeed for the block to succeed:
+/// `all`, `any`, or `first_success` which only keeps the first branch to succeed and cancels the others.
+/// Cancelled branches stop after their running step, and are waited for so that none outlives the block.
+fn run_parallel(
+    ctx: &Context,
+    branches: &[yw::Branch],
+    join: &str,
+    merge: &str,
+    state: &mut State,
+) -> Result<(), Error> {
+    if !["all", "any", "first_success"].contains(&join) { bail!("unsupported join policy: {}", join); }
+    if !["all", "prefixed", "none"].contains(&merge) { bail!("unsupported merge rule: {}", merge); }
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = vec![];
+    for (index, branch) in branches.iter().enumerate() {
+        let name = branch.name.clone().unwrap_or_else(|| format!("branch{}", index + 1));
+        let mut branch_ctx = ctx.clone();
+        branch_ctx.flow_name = format!("{}/{}", ctx.flow_name, name);
+        branch_ctx.cancelled.push(cancel.clone());
+        let mut branch_state = state.clone();
+        let branch = branch.clone();
+        let sender = sender.clone();
+        threads.push(thread::spawn(move || {
+            let succeeded = match run_steps(&branch_ctx, &branch.steps, &mut branch_state) {
+                Ok(()) => true,
+                // ending the flow from a branch only ends that branch
+                Err(err) if matches!(Signal::of(&err), Some(Signal::EndFlow)) => true,
+                Err(err) if matches!(Signal::of(&err), Some(Signal::Cancelled)) => {
+                    log::debug!("[{}] branch cancelled", branch_ctx.flow_name);
+                    false
+                },
+                Err(err) => {
+                    // the branch's own failure steps run here, but the branch still counts as failed
+                    if let Err(err) = handle_failure_with_err(err, &branch_ctx, &branch.upon_failure, &mut branch_state) {
+                        log::warn!("[{}] branch failed: {}", branch_ctx.flow_name, err);
+                    }
+                    false
+                }
+            };
+            let _ = sender.send(BranchOutcome { index, name, succeeded, state: branch_state });
+        }));
+    }
+    drop(sender);
+
+    let mut outcomes = vec![];
+    for outcome in receiver.iter() {
+        if join == "first_success" && outcome.succeeded {
+            outcomes = vec![outcome];
+            break;
+        }
+        outcomes.push(outcome);
+    }
+    cancel.store(true, Ordering::Relaxed);
+    for thread in threads {
+        let _ = thread.join();
+    }
+    if ctx.is_cancelled() {
+        return Err(Signal::Cancelled.into());
+    }
+    outcomes.sort_by_key(|o| o.index);
+
+    let succeeded = outcomes.iter().filter(|o| o.succeeded).count();
+    let joined = match join {
+        "all" => succeeded == branches.len(),
+        _ => succeeded > 0
+    };
+
+    if merge != "none" {
+        // only the variables a branch changed are merged, so that branches don't undo each other's changes
+        let original = state.vars.clone();
+        for outcome in outcomes {
+            for (var_name, value) in outcome.state.vars {
+                if original.get(&var_name) == Some(&value) { continue; }
+                let var_name = if merge == "prefixed" { format!("{}_{}", outcome.name, var_name) } else { var_name };
+                state.vars.insert(var_name, value);
+            }
+        }
+    }
+
+    if !joined {
+        bail!("parallel block failed: {} of {} branches succeeded", succeeded, branches.len());
+    }
+    Ok(())
+}
+
 fn run_steps(
-    notifiers: &HashMap<String, Box<dyn notifiers::Notifier>>,
-    steps: &Vec<yw::Step>,
+    ctx: &Context,
+    steps: &[yw::Step],
     state: &mut State,
-    flow_name: &str
 ) -> Result<(),Error> {
     for step in steps.iter() {
-        match &step {
-            
-            yw::Step::AbortFlow => { log::info!("[{}] aborting flow", flow_name); bail!("Flow aborted")},
-            
-            yw::Step::DebugState => { log::info!("[{}] State dump: {:?}",flow_name,state); },
+        if ctx.is_cancelled() {
+            return Err(Signal::Cancelled.into());
+        }
+        ctx.check_deadline()?;
+        let step_ctx = ctx.for_step(step);
+        let outer_step = std::mem::replace(&mut state.current_step, step.kind());
+        let outcome = run_step(step_ctx.as_ref().unwrap_or(ctx), step, state);
+        state.current_step = outer_step;
+        outcome.map_err(|err| StepFailure::tag(err, step))?;
+    }
+    Ok(())
+}
 
-            yw::Step::Sleep { duration } => {
-                log::info!("[{}] sleeping for {} seconds",flow_name,&duration.as_secs());
-                thread::sleep(*duration);
-            },
+fn run_step(
+    ctx: &Context,
+    step: &yw::Step,
+    state: &mut State,
+) -> Result<(),Error> {
+    let flow_name = ctx.flow_name.as_str();
+    match step {
+        
+        yw::Step::AbortFlow => { log::info!("[{}] aborting flow", flow_name); bail!("Flow aborted")},
+
+        yw::Step::EndFlow => { log::info!("[{}] ending flow early", flow_name); return Err(Signal::EndFlow.into()) },
+
+        yw::Step::Break => return Err(Signal::Break.into()),
+
+        yw::Step::Continue => return Err(Signal::Continue.into()),
+        
+        yw::Step::DebugState => { log::info!("[{}] State dump: {:?}",flow_name,state); },
+
+        yw::Step::Sleep { duration, .. } => {
+            log::info!("[{}] sleeping for {} seconds",flow_name,&duration.as_secs());
+            ctx.sleep(*duration)?;
+        },
+
+        yw::Step::Notify {notifier, message, fail_spec, ..} => {
+            let message = &state.render(message);
+            log::debug!("[{}] sending notification to {}: {}",flow_name, notifier,message);
+            if !ctx.notifiers.contains_key(notifier) {
+                bail!("no notifier found with specified name {}", notifier);
+            }
+            if let Err(err) = retry::retry(&ctx.retry_policy(fail_spec), || ctx.send_message(notifier, message)) {
+                handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?;
+            }
+        },
 
-            yw::Step::Notify {notifier, message, fail_spec} => {
-                log::debug!("[{}] sending notification to {}: {}",flow_name, notifier,message);
-                let notifier = notifiers.get(notifier)
-                    .ok_or(anyhow!("no notifier found with specified name {}", notifier))?;
-                if let Err(err) = notifier.send_message(message) {
-                    handle_failure_with_err(err, notifiers, &fail_spec.upon_failure, state, flow_name)?;
+        yw::Step::GetUrl { url, output_var, fail_spec, .. } => {
+            let url = &state.render(url);
+            log::debug!("[{}] Getting URL {}",flow_name,url);
+            let result = data::simple_get_body(&ctx.http, url, &ctx.retry_policy(fail_spec), ctx.deadline.as_ref().map(|d| d.at));
+
+            match result {
+                Ok(s) =>  state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::PostUrl { url, body, headers, fail_spec, .. } => {
+            let url = &state.render(url);
+            let body = &state.render(body);
+            let headers = &headers.iter().map(|(k,v)| (k.clone(), state.render(v))).collect();
+            log::debug!("[{}] posting to URL {}",flow_name,url);
+            let result = data::post_text(&ctx.http, url, headers, body, &ctx.retry_policy(fail_spec), ctx.deadline.as_ref().map(|d| d.at));
+
+            if let Err(err) = result {
+                handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?;
+            }
+        },
+
+        yw::Step::TextExtractOneCapture { input_var, output_var, regex, fail_spec, .. } => {
+            log::debug!("[{}] applying regex: {}",flow_name,regex);
+            let input = state.get_input(input_var)?;
+
+            let re = Regex::new(regex).map_err(|_| anyhow!("error creating regex from input: {}", regex))?;
+
+            let outcome = re.captures(input);
+            match outcome {
+                Some(captures) => {
+                    let mat = captures.get(1);
+                    if let Some(mat) = mat {
+                        state.set_output(mat.as_str().to_string(), output_var);
+                    } else {
+                        handle_failure_with_err(anyhow!("text not found in text extractor"), ctx, &fail_spec.upon_failure, state)?
+                    }
                 }
-            },
+                None => handle_failure_with_err(anyhow!("text not found in text extractor"), ctx, &fail_spec.upon_failure, state)?
+            };
 
-            yw::Step::GetUrl { url, output_var, fail_spec } => {
-                log::debug!("[{}] Getting URL {}",flow_name,url);
-                let result = data::simple_get_body(
-                    url,
-                    fail_spec.retries.unwrap_or(0), 
-                    fail_spec.retry_interval.unwrap_or(Duration::new(1,0)).as_secs() as f64
-                    ) ;
-
-                match result {
-                    Ok(s) =>  state.set_output(s, output_var),
-                    Err(err) => handle_failure_with_err(err, notifiers, &fail_spec.upon_failure, state, flow_name)?
-                };
-            },
+        },
+        
+        yw::Step::TextExtract { input_var, regex, output_var, all_matches, count_var, fail_spec, .. } => {
+            log::debug!("[{}] applying regex: {}",flow_name,regex);
+            let input = state.get_input(input_var)?;
+
+            match text::extract(input, regex, *all_matches) {
+                Ok(extraction) => {
+                    for (var_name, value) in extraction.named {
+                        state.vars.insert(var_name, value);
+                    }
+                    if let Some(count_var) = count_var {
+                        state.vars.insert(count_var.clone(), extraction.count.to_string());
+                    }
+                    state.set_output(extraction.output, output_var);
+                },
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::Transform { input_var, output_var, operations, fail_spec, .. } => {
+            log::debug!("[{}] transforming text with {} operation(s)",flow_name,operations.len());
+            let input = state.get_input(input_var)?;
+
+            match text::transform(input, operations) {
+                Ok(s) => state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
 
-            yw::Step::PostUrl { url, body, headers, fail_spec } => {
-                log::debug!("[{}] posting to URL {}",flow_name,url);
-                let result = data::post_text(url, headers, body, 
-                    fail_spec.retries.unwrap_or(0),
-                    fail_spec.retry_interval.unwrap_or(Duration::new(1,0)).as_secs() as f64
-                    ) ;
+        yw::Step::JsonExtract { input_var, path, output_var, paths, fail_spec, .. } => {
+            log::debug!("[{}] extracting values from JSON",flow_name);
+            if path.is_none() && paths.is_empty() { bail!("json_extract needs a path or paths to extract"); }
+            let input = state.get_input(input_var)?;
 
-                if let Err(err) = result {
-                    handle_failure_with_err(err, notifiers, &fail_spec.upon_failure, state, flow_name)?;
+            let outcome = json::parse(input).and_then(|document| {
+                let mut values = vec![];
+                if let Some(path) = path {
+                    values.push((output_var.clone(), json::query(&document, path)?));
                 }
-            },
+                for (var_name, path) in paths {
+                    values.push((Some(var_name.clone()), json::query(&document, path)?));
+                }
+                Ok(values)
+            });
+            match outcome {
+                Ok(values) => for (var_name, value) in values { state.set_output(value, &var_name) },
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
 
-            yw::Step::TextExtractOneCapture { input_var, output_var, regex, fail_spec } => {
-                log::debug!("[{}] applying regex: {}",flow_name,regex);
-                let input = state.get_input(input_var)?;
+        yw::Step::PrometheusExtract { input_var, selector, aggregate, output_var, fail_spec, .. } => {
+            log::debug!("[{}] selecting metrics: {}",flow_name,selector);
+            let input = state.get_input(input_var)?;
 
-                let re = Regex::new(regex).map_err(|_| anyhow!("error creating regex from input: {}", regex))?;
+            match prometheus::extract(input, selector, aggregate.as_deref()) {
+                Ok(value) => state.set_output(value.to_string(), output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
 
-                let outcome = re.captures(input);
-                match outcome {
-                    Some(captures) => {
-                        let mat = captures.get(1);
-                        if let Some(mat) = mat {
-                            state.set_output(mat.as_str().to_string(), output_var);
-                        } else {
-                            handle_failure_with_err(anyhow!("text not found in text extractor"), notifiers, &fail_spec.upon_failure, state, flow_name)?
-                        }
-                    }
-                    None => handle_failure_with_err(anyhow!("text not found in text extractor"), notifiers, &fail_spec.upon_failure, state, flow_name)?
-                };
+        yw::Step::HtmlExtract { input_var, selector, attribute, output_var, all_matches, fail_spec, .. } => {
+            log::debug!("[{}] selecting HTML elements: {}",flow_name,selector);
+            let input = state.get_input(input_var)?;
 
-            },
-            
-            yw::Step::CompareVar { input_var, compare_with, compare_for, if_true, if_false } => {
-                log::debug!("[{}] comparing one variable: {}",flow_name, input_var.as_deref().unwrap_or("<last output>"));
-                let input = state.get_input(input_var)?;
+            match markup::html_extract(input, selector, attribute.as_deref(), *all_matches) {
+                Ok(s) => state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
 
-                let outcome = match compare_for.as_str() {
-                    "equality" => input.as_str() == compare_with.as_str(),
-                    _ => bail!("unsupported comparison type: {}",compare_for)
-                };
+        yw::Step::XmlExtract { input_var, xpath, namespaces, output_var, all_matches, fail_spec, .. } => {
+            log::debug!("[{}] evaluating XPath: {}",flow_name,xpath);
+            let input = state.get_input(input_var)?;
+
+            match markup::xml_extract(input, xpath, namespaces, *all_matches) {
+                Ok(s) => state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::CsvExtract { input_var, path, delimiter, has_headers, filter, columns, output_var, aggregates, fail_spec, .. } => {
+            log::debug!("[{}] extracting from CSV {}",flow_name, path.as_deref().unwrap_or("input"));
+            let input = match path {
+                Some(path) => files::read_file(path, &ctx.retry_policy(fail_spec)),
+                None => Ok(state.get_input(input_var)?.clone())
+            };
+
+            let outcome = input.and_then(|input| {
+                let mut table = tables::Table::parse(&input, *delimiter, *has_headers)?;
+                table.filter(filter)?;
+                let mut values = vec![];
+                for (var_name, aggregate) in aggregates {
+                    values.push((var_name.clone(), table.aggregate(aggregate.column.as_deref(), &aggregate.aggregate)?));
+                }
+                Ok((table.select(columns)?, values))
+            });
+            match outcome {
+                Ok((rows, values)) => {
+                    state.vars.extend(values);
+                    state.set_output(rows, output_var);
+                },
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::Compute { expression, output_var, fail_spec, .. } => {
+            log::debug!("[{}] computing {}",flow_name,expression);
+            match expr::evaluate(expression, &state.vars) {
+                Ok(value) => state.set_output(value.to_string(), output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::Datetime { operation, input_var, other_var, output_var, options, fail_spec, .. } => {
+            log::debug!("[{}] date operation: {}",flow_name,operation);
+            let input = match operation.as_str() {
+                "now" => None,
+                _ => Some(state.get_input(input_var)?.as_str())
+            };
+            let other = other_var.as_ref()
+                .map(|var_name| state.vars.get(var_name).map(String::as_str).ok_or(anyhow!("unknown variable {}", var_name)))
+                .transpose()?;
+
+            match datetime::operate(operation, input, other, options) {
+                Ok(s) => state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::Encode { input_var, operation, secret, digest_format, output_var, fail_spec, .. } => {
+            log::debug!("[{}] encoding with {}",flow_name,operation);
+            let input = state.get_input(input_var)?;
+
+            match encode::encode(input, operation, secret.as_deref(), digest_format.as_deref()) {
+                Ok(s) => state.set_output(s, output_var),
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::CompareVar { input_var, compare_with, compare_for, if_true, if_false, .. } => {
+            log::debug!("[{}] comparing one variable: {}",flow_name, input_var.as_deref().unwrap_or("<last output>"));
+            let input = state.get_input(input_var)?;
+
+            let outcome = compare::compare(input, compare_for, &state.render(compare_with))?;
 
-                match (outcome, if_true.is_empty(), if_false.is_empty()) {
-                    (true, false, _) => {
-                        log::debug!("[{}] entering a sub-flow after comparison was true", flow_name);
-                        run_steps(notifiers, if_true, state, flow_name)?;
-                        log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+            match (outcome, if_true.is_empty(), if_false.is_empty()) {
+                (true, false, _) => {
+                    log::debug!("[{}] entering a sub-flow after comparison was true", flow_name);
+                    run_steps(ctx, if_true, state)?;
+                    log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+                },
+                (false, _, false) => {
+                    log::debug!("[{}] entering a sub-flow after comparison was false", flow_name);
+                    run_steps(ctx, if_false, state)?;
+                    log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+                },
+                _ => log::debug!("[{}] no action taken as result of comparison", flow_name)
+            };
+            
+        },
+
+        yw::Step::Switch { input_var, cases, default, .. } => {
+            log::debug!("[{}] matching one variable against {} cases: {}", flow_name, cases.len(), input_var.as_deref().unwrap_or("<last output>"));
+            let input = state.get_input(input_var)?.clone();
+            let mut matched = None;
+            for (position, case) in cases.iter().enumerate() {
+                // a value that cannot be compared this way (e.g. text against a numeric range) does not match
+                match compare::compare(&input, &case.compare_for, &state.render(&case.compare_with)) {
+                    Ok(true) => {
+                        matched = Some((position, &case.steps));
+                        break;
                     },
-                    (false, _, false) => {
-                        log::debug!("[{}] entering a sub-flow after comparison was false", flow_name);
-                        run_steps(notifiers, if_false, state, flow_name)?;
-                        log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+                    Ok(false) => (),
+                    Err(err) => log::debug!("[{}] case {} does not match: {}", flow_name, position + 1, err)
+                }
+            }
+            match matched {
+                Some((position, steps)) => {
+                    log::debug!("[{}] entering the sub-flow of case {}", flow_name, position + 1);
+                    run_steps(ctx, steps, state)?;
+                },
+                None => {
+                    log::debug!("[{}] no case matched, entering the default sub-flow", flow_name);
+                    run_steps(ctx, default, state)?;
+                }
+            };
+            log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+        },
+
+        yw::Step::If { condition, then, otherwise, .. } => {
+            log::debug!("[{}] evaluating condition: {}",flow_name, condition);
+            let outcome = expr::evaluate(condition, &state.vars)?.as_bool()?;
+            let branch = if outcome { then } else { otherwise };
+            if branch.is_empty() {
+                log::debug!("[{}] no action taken as condition was {}", flow_name, outcome);
+            } else {
+                log::debug!("[{}] entering a sub-flow after condition was {}", flow_name, outcome);
+                run_steps(ctx, branch, state)?;
+                log::debug!("[{}] Sub-flow completed, resuming main flow", flow_name);
+            }
+        },
+
+        yw::Step::Try { steps, catch, finally, .. } => {
+            log::debug!("[{}] entering a try block", flow_name);
+            let outcome = match run_steps(ctx, steps, state) {
+                Ok(()) => Ok(()),
+                Err(err) => handle_failure_with_err(err, ctx, catch, state),
+            };
+            if !finally.is_empty() {
+                log::debug!("[{}] running the finally steps", flow_name);
+                run_steps(ctx, finally, state)?;
+            }
+            outcome?;
+            log::debug!("[{}] Try block completed, resuming main flow", flow_name);
+        },
+
+        yw::Step::ForEach { input_var, format, regex, items, item_var, index_var, steps, continue_on_error, failures_var, fail_spec, .. } => {
+            let items = match items {
+                Some(items) => items.clone(),
+                None => text::split_items(state.get_input(input_var)?, format.as_deref(), regex.as_deref())?
+            };
+            log::debug!("[{}] looping over {} item(s)", flow_name, items.len());
+
+            let mut failures = vec![];
+            let mut last_error = None;
+            for (index, item) in items.into_iter().enumerate() {
+                state.vars.insert(item_var.clone(), item.clone());
+                state.vars.insert(index_var.clone(), index.to_string());
+                match run_iteration(ctx, steps, state) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        log::debug!("[{}] loop stopped by a break step at item {}", flow_name, item);
+                        break;
                     },
-                    _ => log::debug!("[{}] no action taken as result of comparison", flow_name)
+                    Err(err) if Signal::of(&err).is_some() => return Err(err),
+                    Err(err) => {
+                        log::debug!("[{}] loop failed for item {}", flow_name, item);
+                        failures.push(item);
+                        last_error = Some(err);
+                        if !*continue_on_error { break; }
+                    }
+                }
+            }
+
+            if let Some(failures_var) = failures_var {
+                state.vars.insert(failures_var.clone(), text::to_list(failures.clone()));
+            }
+            if let Some(err) = last_error {
+                let err = match failures.len() {
+                    1 => err,
+                    n => anyhow!("loop failed for {} items, last error: {}", n, err)
                 };
-                
-            },
+                handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?;
+            }
+            log::debug!("[{}] loop completed, resuming main flow", flow_name);
+        },
 
-            yw::Step::ReadFromFile { path, output_var, fail_spec} => {
-                log::debug!("[{}] reading from file {}", flow_name, path);
-                let contents = files::read_file_with_retries(path, fail_spec.retries, fail_spec.retry_interval);
-                match contents {
-                    Ok(s) => state.set_output(s, output_var),
-                    Err(e) => handle_failure_with_err(e, notifiers, &fail_spec.upon_failure, state, flow_name)?
-                }
-            },
+        yw::Step::WaitUntil { condition, probe, interval, fail_spec, timeout } => {
+            log::debug!("[{}] waiting until {}", flow_name, condition);
+            if let Err(err) = wait_until(ctx, condition, probe, *interval, timeout.duration, state) {
+                handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?;
+            }
+        },
+
+        yw::Step::RepeatUntil { spec, .. } => {
+            log::debug!("[{}] repeating steps until {}", flow_name, spec.condition);
+            run_loop(ctx, spec, state, false)?;
+        },
+
+        yw::Step::While { spec, .. } => {
+            log::debug!("[{}] repeating steps while {}", flow_name, spec.condition);
+            run_loop(ctx, spec, state, true)?;
+        },
+
+        yw::Step::Parallel { branches, join, merge, fail_spec, .. } => {
+            log::debug!("[{}] running {} branches in parallel", flow_name, branches.len());
+            if let Err(err) = run_parallel(ctx, branches, join, merge, state) {
+                handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?;
+            }
+            log::debug!("[{}] parallel branches completed, resuming main flow", flow_name);
+        },
+
+        yw::Step::Retry { steps, policy, upon_failure, .. } => {
+            log::debug!("[{}] running steps with up to {} attempts", flow_name, policy.max_attempts);
+            // end_flow, break and continue are not failures, they go through without retries
+            let mut signal = None;
+            let outcome = retry::retry(&ctx.within_deadline(policy.clone()), || match run_steps(ctx, steps, state) {
+                Err(err) if Signal::of(&err).is_some() => { signal = Some(err); Ok(()) },
+                other => other
+            });
+            if let Some(signal) = signal {
+                return Err(signal);
+            }
+            if let Err(err) = outcome {
+                handle_failure_with_err(err, ctx, upon_failure, state)?;
+            }
+            log::debug!("[{}] retried steps completed, resuming main flow", flow_name);
+        },
+
+        yw::Step::Call { function, params, output_var, fail_spec, .. } => {
+            log::debug!("[{}] calling function {}", flow_name, function);
+            let definition = ctx.functions.get(function).ok_or(anyhow!("no function found with name {}", function))?;
+            if ctx.call_depth >= MAX_CALL_DEPTH {
+                bail!("function {} called more than {} levels deep", function, MAX_CALL_DEPTH);
+            }
+            if let Some(unknown) = params.keys().find(|p| !definition.params.contains(p)) {
+                bail!("function {} has no parameter {}", function, unknown);
+            }
+
+            let mut function_state = State::default();
+            for param in &definition.params {
+                let value = params.get(param).ok_or(anyhow!("missing parameter {} calling function {}", param, function))?;
+                function_state.vars.insert(param.clone(), state.render(value));
+            }
+            let mut function_ctx = ctx.clone();
+            function_ctx.call_depth += 1;
 
-            yw::Step::AppendToFile { path, input_var, fail_spec } => {
-                log::debug!("[{}] writing (appending) to file {}", flow_name, path);
-                let input = state.get_input(input_var)?;
-                if let Err(e) = files::append_to_file_with_retries(path, input, fail_spec.retries, fail_spec.retry_interval) {
-                    handle_failure_with_err(e, notifiers, steps, state, flow_name)?
+            match run_steps(&function_ctx, &definition.steps, &mut function_state) {
+                Ok(()) => {
+                    for var_name in &definition.returns {
+                        if let Some(value) = function_state.vars.remove(var_name) {
+                            state.vars.insert(var_name.clone(), value);
+                        }
+                    }
+                    if let Some(output) = function_state.last_output {
+                        state.set_output(output, output_var);
+                    }
+                    log::debug!("[{}] function {} completed, resuming main flow", flow_name, function);
+                },
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::OnChange { input_var, key, previous_var, trigger_on_first_run, if_changed, if_unchanged, .. } => {
+            log::debug!("[{}] checking variable {} for changes",flow_name, input_var);
+            let current = state.get_input(&Some(input_var.clone()))?.clone();
+            let key = key.as_ref().map_or_else(|| input_var.clone(), |key| state.render(key));
+            let previous = ctx.store.previous(&key);
+
+            let changed = match &previous {
+                Some(previous) => *previous != current,
+                None => *trigger_on_first_run
+            };
+            if let Some(previous_var) = previous_var {
+                state.vars.insert(previous_var.clone(), previous.unwrap_or_default());
+            }
+            let branch = if changed { if_changed } else { if_unchanged };
+            let outcome = if branch.is_empty() {
+                log::debug!("[{}] no action taken, variable changed: {}", flow_name, changed);
+                Ok(())
+            } else {
+                log::debug!("[{}] entering a sub-flow, variable changed: {}", flow_name, changed);
+                run_steps(ctx, branch, state)
+            };
+            // the value only counts as seen once its branch went through, so that e.g. an alert that could not
+            // be sent goes out on the next run
+            match &outcome {
+                Err(err) if Signal::of(err).is_none() => (),
+                _ => ctx.store.record(&key, &current)
+            }
+            outcome?;
+        },
+
+        yw::Step::Diff { input_var, other_var, key, mode, context_lines, output_var, added_var, removed_var, fail_spec, .. } => {
+            log::debug!("[{}] computing differences",flow_name);
+            let new = state.get_input(input_var)?.clone();
+            let old = match other_var {
+                Some(var_name) => state.get_input(&Some(var_name.clone()))?.clone(),
+                None => {
+                    let key = key.as_ref().map(|key| state.render(key)).or(input_var.clone())
+                        .ok_or(anyhow!("diff against the previous run needs an input_var or a key"))?;
+                    ctx.store.record(&key, &new);
+                    // on the first run there is nothing to compare with, so nothing changed
+                    ctx.store.previous(&key).unwrap_or_else(|| new.clone())
                 }
-            },
+            };
 
-            yw::Step::SetVariable { output_var, input } => {
-                log::debug!("[{}] setting variable {}", flow_name, output_var.as_deref().unwrap_or("<unnamed>"));
-                state.set_output(input.clone(), output_var);
+            match diff::diff(&old, &new, mode, *context_lines) {
+                Ok(changes) => {
+                    if let Some(added_var) = added_var { state.vars.insert(added_var.clone(), changes.added.to_string()); }
+                    if let Some(removed_var) = removed_var { state.vars.insert(removed_var.clone(), changes.removed.to_string()); }
+                    state.set_output(changes.diff, output_var);
+                },
+                Err(err) => handle_failure_with_err(err, ctx, &fail_spec.upon_failure, state)?
+            };
+        },
+
+        yw::Step::ReadFromFile { path, output_var, fail_spec, .. } => {
+            log::debug!("[{}] reading from file {}", flow_name, path);
+            let contents = files::read_file(path, &ctx.retry_policy(fail_spec));
+            match contents {
+                Ok(s) => state.set_output(s, output_var),
+                Err(e) => handle_failure_with_err(e, ctx, &fail_spec.upon_failure, state)?
             }
+        },
 
+        yw::Step::AppendToFile { path, input_var, fail_spec, .. } => {
+            log::debug!("[{}] writing (appending) to file {}", flow_name, path);
+            let input = state.get_input(input_var)?;
+            if let Err(e) = files::append_to_file(path, input, &ctx.retry_policy(fail_spec)) {
+                handle_failure_with_err(e, ctx, &fail_spec.upon_failure, state)?
+            }
+        },
+
+        yw::Step::SetVariable { output_var, input, .. } => {
+            log::debug!("[{}] setting variable {}", flow_name, output_var.as_deref().unwrap_or("<unnamed>"));
+            state.set_output(input.clone(), output_var);
         }
-    }
 
+    }
     Ok(())
 }
 
+/// Runs a flow once: its steps, within its max_duration, then its upon_success or upon_failure steps and
+/// its finally steps
+fn run(ctx: &Context, workflow: &yw::YamlWorkflow) -> Result<(), Error> {
+    let flow_name = ctx.flow_name.as_str();
+    log::info!("[{}] starting flow", &flow_name);
+
+    let started = Instant::now();
+    let mut state = State::default();
+    let mut run_ctx = ctx.clone();
+    run_ctx.deadline = workflow.max_duration.map(|d| Deadline::after(d, format!("flow timed out after {:?}", d)));
+    run_ctx.handler_deadline = run_ctx.deadline.clone();
+    let mut outcome = ended_early_is_ok(run_steps(&run_ctx, &workflow.steps, &mut state));
+    let timed_out = outcome.is_err() && run_ctx.deadline.as_ref().is_some_and(|d| Instant::now() >= d.at);
+    let run_outcome = match (&outcome, timed_out) {
+        (Ok(()), _) => "success",
+        (Err(_), true) => "timeout",
+        (Err(_), false) => "failure",
+    };
+    state.vars.insert("run_outcome".to_string(), run_outcome.to_string());
+    state.vars.insert("run_duration".to_string(), format!("{:.3}", started.elapsed().as_secs_f64()));
+    // hooks run with no deadline, so that a timed-out run can still be reported and cleaned up after
+    match &outcome {
+        Ok(()) => {
+            log::info!("[{}] Flow completed", &flow_name);
+            if !workflow.upon_success.is_empty() {
+                log::debug!("[{}] starting success steps", &flow_name);
+                outcome = ended_early_is_ok(run_steps(ctx, &workflow.upon_success, &mut state));
+                if let Err(e) = &outcome {
+                    log::warn!("[{}] Success steps failed. Last error: {}", &flow_name, e);
+                    // finally steps see the run as failed
+                    state.vars.insert("run_outcome".to_string(), "failure".to_string());
+                    state.set_error(e);
+                }
+            }
+        },
+        Err(e) => {
+            if timed_out {
+                log::error!("[{}] Flow run TIMED OUT, it ran for more than its max_duration", &flow_name);
+            }
+            if !workflow.upon_failure.is_empty() {
+                log::warn!("[{}] Flow failed, starting fallback steps. Last error: {}", &flow_name, e);
+                state.set_error(e);
+                outcome = ended_early_is_ok(run_steps(ctx, &workflow.upon_failure, &mut state));
+                match &outcome {
+                    Ok(()) => log::info!("[{}] Fallback flow completed", &flow_name),
+                    Err(e) => {
+                        log::warn!("[{}] Flow aborted due to error. Last error: {}", &flow_name, e)
+                    }
+                }
+            } else {
+                log::warn!("[{}] Flow aborted due to error. Last error: {}", &flow_name, e)
+            }
+        }
+    };
+    if !workflow.finally.is_empty() {
+        log::debug!("[{}] starting finally steps", &flow_name);
+        state.vars.insert("run_duration".to_string(), format!("{:.3}", started.elapsed().as_secs_f64()));
+        if let Err(e) = ended_early_is_ok(run_steps(ctx, &workflow.finally, &mut state)) {
+            log::warn!("[{}] Finally steps failed. Last error: {}", &flow_name, e);
+            outcome = outcome.and(Err(e));
+        }
+    }
+    outcome
+}
+
 pub fn start(yaml_workflow: yw::YamlWorkflow) -> Vec<triggers::Thread> {
     let yaml_workflow = Arc::new(yaml_workflow);
     let flow_name = yaml_workflow.flow_name.as_deref() .unwrap_or("<unnamed>") .to_string();
@@ -190,31 +1042,25 @@ pub fn start(yaml_workflow: yw::YamlWorkflow) -> Vec<triggers::Thread> {
         }))
         .collect();
 
+    let http = data::HttpSession::new(yaml_workflow.cookie_file.clone())
+        .unwrap_or_else(|e| panic!("[{}] {}", flow_name, e));
+
+    let store = persist::Store::open(yaml_workflow.state_file.clone().unwrap_or_else(|| persist::Store::default_path(&flow_name)))
+        .unwrap_or_else(|e| panic!("[{}] {}", flow_name, e));
+
+    let ctx = Context { flow_name, notifiers: Arc::new(notifiers), http, store: Arc::new(store), cancelled: vec![],
+        functions: Arc::new(yaml_workflow.functions.clone()), call_depth: 0, deadline: None, handler_deadline: None };
+
     let workflow_for_closure = yaml_workflow.clone();
     
     let rule  =   move || { 
-        let flow_name = workflow_for_closure.flow_name.as_deref() .unwrap_or("<unnamed>") .to_string();
-        log::info!("[{}] starting flow", &flow_name);
-
-        let mut state = State::default();
-        let mut outcome = run_steps(&notifiers, &workflow_for_closure.steps, &mut state, &flow_name);
-        match &outcome {
-            Ok(()) => log::info!("[{}] Flow completed", &flow_name),
-            Err(e) => {
-                if !workflow_for_closure.upon_failure.is_empty() {
-                    log::warn!("[{}] Flow failed, starting fallback steps. Last error: {}", &flow_name, e);
-                    outcome = run_steps(&notifiers, &workflow_for_closure.upon_failure, &mut state, &flow_name);
-                    match &outcome {
-                        Ok(()) => log::info!("[{}] Fallback flow completed", &flow_name),
-                        Err(e) => {
-                            log::warn!("[{}] Flow aborted due to error. Last error: {}", &flow_name, e)
-                        }
-                    }
-                } else {
-                    log::warn!("[{}] Flow aborted due to error. Last error: {}", &flow_name, e)
-                }
-            }
-        };
+        let outcome = run(&ctx, &workflow_for_closure);
+        if let Err(e) = ctx.http.save_cookies() {
+            log::warn!("[{}] {}", &ctx.flow_name, e);
+        }
+        if let Err(e) = ctx.store.commit() {
+            log::warn!("[{}] {}", &ctx.flow_name, e);
+        }
         outcome
 
     };
@@ -238,4 +1084,676 @@ pub fn start(yaml_workflow: yw::YamlWorkflow) -> Vec<triggers::Thread> {
     };
 
     triggers.into_iter().map(|t| t.thread()).collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands the messages a flow sends over to the test
+    struct Messages(Arc<notifiers::MemoryNotifier>);
+
+    impl notifiers::Notifier for Messages {
+        fn send_message(&self, msg: &str) -> Result<(), Error> {
+            self.0.send_message(msg)
+        }
+    }
+
+    /// A notifier stuck on a connection that never answers
+    struct Hanging;
+
+    impl notifiers::Notifier for Hanging {
+        fn send_message(&self, _msg: &str) -> Result<(), Error> {
+            thread::sleep(Duration::from_secs(5));
+            Ok(())
+        }
+    }
+
+    /// A context with `memory` and `hanging` notifiers, and a state file of its own, named after the test
+    fn context(test_name: &str) -> (Context, Arc<notifiers::MemoryNotifier>) {
+        let memory = Arc::new(notifiers::MemoryNotifier::new());
+        let mut notifiers: HashMap<String, Box<dyn notifiers::Notifier>> = HashMap::new();
+        notifiers.insert("memory".to_string(), Box::new(Messages(memory.clone())));
+        notifiers.insert("hanging".to_string(), Box::new(Hanging));
+        let path = std::env::temp_dir().join(format!("m7m_test_{}.state.json", test_name)).to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        let ctx = Context { flow_name: test_name.to_string(), notifiers: Arc::new(notifiers), http: data::HttpSession::new(None).unwrap(),
+            store: Arc::new(persist::Store::open(path).unwrap()), cancelled: vec![], functions: Arc::new(HashMap::new()),
+            call_depth: 0, deadline: None, handler_deadline: None };
+        (ctx, memory)
+    }
+
+    /// Parses steps the way flow files are
+    fn steps(yaml: &str) -> Vec<yw::Step> {
+        serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(yaml)).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let mut state = State::default();
+        state.vars.insert("host".to_string(), "router".to_string());
+        state.vars.insert("status".to_string(), "down".to_string());
+        assert_eq!(state.render("{{host}} is {{ status }}"), "router is down");
+        assert_eq!(state.render("{{ missing }} and {{host}}"), "{{ missing }} and router");
+        assert_eq!(state.render("{ host } {{host-name}}"), "{ host } {{host-name}}");
+    }
+
+    #[test]
+    fn test_on_change_keys() {
+        let (ctx, memory) = context("on_change_keys");
+        let flow = steps(r#"
+- for_each:
+    items: [web, db]
+    steps:
+      - set_variable:
+          output_var: status
+          input: up
+      - on_change:
+          input_var: status
+          key: "status_{{ item }}"
+          trigger_on_first_run: true
+          if_changed:
+            - notify:
+                notifier: memory
+                message: "{{ item }} is {{ status }}"
+"#);
+        run_steps(&ctx, &flow, &mut State::default()).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec!["web is up", "db is up"]);
+        ctx.store.commit().unwrap();
+        assert_eq!(ctx.store.previous("status_db").as_deref(), Some("up"));
+        assert_eq!(ctx.store.previous("status"), None);
+    }
+
+    #[test]
+    fn test_loops() {
+        let (ctx, memory) = context("loops");
+        let mut state = State::default();
+        state.vars.insert("count".to_string(), "0".to_string());
+        // the last allowed iteration makes the condition false, so the loop did not give up
+        let flow = steps(r#"
+- while:
+    condition: count < 3
+    max_iterations: 3
+    steps:
+      - compute:
+          expression: count + 1
+          output_var: count
+"#);
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(state.vars["count"], "3");
+
+        let flow = steps(r#"
+- while:
+    condition: count < 10
+    max_iterations: 2
+    steps:
+      - compute:
+          expression: count + 1
+          output_var: count
+"#);
+        let err = run_steps(&ctx, &flow, &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "loop gave up after 2 iterations: count < 10");
+        assert_eq!(state.vars["count"], "5");
+
+        let flow = steps(r#"
+- repeat_until:
+    condition: count >= 6
+    max_iterations: 3
+    iteration_var: round
+    steps:
+      - compute:
+          expression: count + 1
+          output_var: count
+    on_exhausted:
+      - notify:
+          notifier: memory
+          message: "gave up after {{ round }} rounds"
+- repeat_until:
+    condition: round == 2
+    iteration_var: round
+    steps:
+      - notify:
+          notifier: memory
+          message: "round {{ round }}"
+"#);
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(state.vars["count"], "6");
+        assert_eq!(memory.get_saved_messages(), vec!["round 1", "round 2"]);
+
+        state.vars.insert("count".to_string(), "0".to_string());
+        let flow = steps(r#"
+- repeat_until:
+    condition: count >= 5
+    max_iterations: 2
+    steps:
+      - compute:
+          expression: count + 1
+          output_var: count
+    on_exhausted:
+      - notify:
+          notifier: memory
+          message: "gave up at {{ count }}"
+"#);
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(memory.get_saved_messages().last().unwrap(), "gave up at 2");
+    }
+
+    #[test]
+    fn test_parallel() {
+        let (ctx, memory) = context("parallel");
+        let branches = r#"
+    branches:
+      - name: web
+        steps:
+          - set_variable:
+              output_var: status
+              input: up
+      - name: db
+        steps:
+          - set_variable:
+              output_var: latency
+              input: "12"
+"#;
+        let mut state = State::default();
+        run_steps(&ctx, &steps(&format!("- parallel:{}", branches)), &mut state).unwrap();
+        assert_eq!((state.vars["status"].as_str(), state.vars["latency"].as_str()), ("up", "12"));
+
+        let mut state = State::default();
+        run_steps(&ctx, &steps(&format!("- parallel:\n    merge: prefixed{}", branches)), &mut state).unwrap();
+        assert_eq!((state.vars["web_status"].as_str(), state.vars["db_latency"].as_str()), ("up", "12"));
+        assert!(!state.vars.contains_key("status"));
+
+        let mut state = State::default();
+        run_steps(&ctx, &steps(&format!("- parallel:\n    merge: none{}", branches)), &mut state).unwrap();
+        assert!(state.vars.is_empty());
+
+        let failing = r#"
+    branches:
+      - steps:
+          - abort_flow
+        upon_failure:
+          - notify:
+              notifier: memory
+              message: "branch failed: {{ error }}"
+      - steps:
+          - set_variable:
+              output_var: status
+              input: up
+    upon_failure:
+      - notify:
+          notifier: memory
+          message: "{{ error }}"
+"#;
+        let mut state = State::default();
+        run_steps(&ctx, &steps(&format!("- parallel:\n    join: any{}", failing)), &mut state).unwrap();
+        run_steps(&ctx, &steps(&format!("- parallel:{}", failing)), &mut state).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec![
+            "branch failed: Flow aborted", "branch failed: Flow aborted", "parallel block failed: 1 of 2 branches succeeded"]);
+        assert_eq!(state.vars["status"], "up");
+    }
+
+    #[test]
+    fn test_parallel_first_success() {
+        let (ctx, memory) = context("parallel_first_success");
+        let flow = steps(r#"
+- parallel:
+    join: first_success
+    branches:
+      - name: slow
+        steps:
+          - sleep:
+              duration: 2s
+          - notify:
+              notifier: memory
+              message: slow
+      - name: fast
+        steps:
+          - notify:
+              notifier: memory
+              message: fast
+          - set_variable:
+              output_var: winner
+              input: fast
+"#);
+        let mut state = State::default();
+        let started = Instant::now();
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        // the slow branch is cancelled, and stops well before its sleep would end
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(state.vars["winner"], "fast");
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(memory.get_saved_messages(), vec!["fast"]);
+    }
+
+    #[test]
+    fn test_call() {
+        let (mut ctx, memory) = context("call");
+        ctx.functions = Arc::new(serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(r#"
+alert:
+  params: [host, status]
+  returns: [line]
+  steps:
+    - set_variable:
+        output_var: line
+        input: sent
+    - notify:
+        notifier: memory
+        message: "{{ host }} is {{ status }} (secret: {{ secret }})"
+    - set_variable:
+        output_var: unreturned
+        input: "x"
+    - set_variable:
+        input: done
+down:
+  steps:
+    - call:
+        function: down
+"#)).unwrap());
+        let mut state = State::default();
+        state.vars.insert("name".to_string(), "router".to_string());
+        state.vars.insert("secret".to_string(), "hidden".to_string());
+        let flow = steps(r#"
+- call:
+    function: alert
+    output_var: result
+    params:
+      host: "{{ name }}"
+      status: down
+"#);
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        // the function only sees its parameters, and only hands back what it returns
+        assert_eq!(memory.get_saved_messages(), vec!["router is down (secret: {{ secret }})"]);
+        assert_eq!(state.vars["line"], "sent");
+        assert_eq!(state.vars["result"], "done");
+        assert!(!state.vars.contains_key("unreturned") && !state.vars.contains_key("host"));
+
+        let err = run_steps(&ctx, &steps("- call:\n    function: alert\n    params:\n      host: router"), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "missing parameter status calling function alert");
+        let err = run_steps(&ctx, &steps("- call:\n    function: alert\n    params:\n      hots: router"), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "function alert has no parameter hots");
+        let err = run_steps(&ctx, &steps("- call:\n    function: nope"), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "no function found with name nope");
+
+        let flow = steps(r#"
+- call:
+    function: down
+    upon_failure:
+      - notify:
+          notifier: memory
+          message: "{{ error }}"
+"#);
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(memory.get_saved_messages().last().unwrap(), "function down called more than 32 levels deep");
+    }
+
+    #[test]
+    fn test_try() {
+        let (ctx, memory) = context("try");
+        let flow = steps(r#"
+- try:
+    steps:
+      - try:
+          steps:
+            - read_from_file:
+                path: /nonexistent/m7m
+          finally:
+            - notify:
+                notifier: memory
+                message: inner finally
+      - notify:
+          notifier: memory
+          message: not reached
+    catch:
+      - notify:
+          notifier: memory
+          message: "{{ error_step }} failed: {{ error }}"
+    finally:
+      - notify:
+          notifier: memory
+          message: outer finally
+- notify:
+    notifier: memory
+    message: resumed
+"#);
+        run_steps(&ctx, &flow, &mut State::default()).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec![
+            "inner finally", "read_from_file failed: could not read file /nonexistent/m7m", "outer finally", "resumed"]);
+
+        // without catch, the error goes on once finally ran, and a failing catch fails the step too
+        let flow = steps(r#"
+- try:
+    steps:
+      - abort_flow
+    finally:
+      - set_variable:
+          output_var: cleaned
+          input: "yes"
+"#);
+        let mut state = State::default();
+        let err = run_steps(&ctx, &flow, &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "Flow aborted");
+        assert_eq!(state.vars["cleaned"], "yes");
+        let flow = steps("- try:\n    steps:\n      - abort_flow\n    catch:\n      - compute:\n          expression: 1 +");
+        assert!(run_steps(&ctx, &flow, &mut State::default()).is_err());
+    }
+
+    #[test]
+    fn test_switch() {
+        let (ctx, memory) = context("switch");
+        let flow = steps(r#"
+- switch:
+    input_var: code
+    cases:
+      - compare_with: "200"
+      - compare_for: between
+        compare_with: "500..599"
+        steps:
+          - notify:
+              notifier: memory
+              message: "server error {{ code }}"
+      - compare_for: starts_with
+        compare_with: "{{ prefix }}"
+        steps:
+          - notify:
+              notifier: memory
+              message: "client error {{ code }}"
+    default:
+      - notify:
+          notifier: memory
+          message: "unexpected {{ code }}"
+"#);
+        let mut state = State::default();
+        state.vars.insert("prefix".to_string(), "4".to_string());
+        for code in ["200", "503", "404", "timeout", "302"] {
+            state.vars.insert("code".to_string(), code.to_string());
+            run_steps(&ctx, &flow, &mut state).unwrap();
+        }
+        // a value that cannot be compared as a number does not match the range, it is not an error
+        assert_eq!(memory.get_saved_messages(), vec!["server error 503", "client error 404", "unexpected timeout", "unexpected 302"]);
+    }
+
+    #[test]
+    fn test_break_continue_end_flow() {
+        let (ctx, memory) = context("break_continue_end_flow");
+        let flow = steps(r#"
+- for_each:
+    items: [a, skip, b, stop, c]
+    steps:
+      - switch:
+          input_var: item
+          cases:
+            - compare_with: skip
+              steps:
+                - continue
+            - compare_with: stop
+              steps:
+                - break
+      - notify:
+          notifier: memory
+          message: "{{ item }}"
+- set_variable:
+    output_var: count
+    input: "0"
+- while:
+    condition: "true"
+    steps:
+      - compute:
+          expression: count + 1
+          output_var: count
+      - if:
+          condition: count == 3
+          then:
+            - break
+"#);
+        let mut state = State::default();
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec!["a", "b"]);
+        assert_eq!(state.vars["count"], "3");
+
+        // end_flow is a success: finally steps still run, catch steps don't
+        let flow = steps(r#"
+- try:
+    steps:
+      - end_flow
+    catch:
+      - notify:
+          notifier: memory
+          message: caught
+    finally:
+      - notify:
+          notifier: memory
+          message: finally
+- notify:
+    notifier: memory
+    message: not reached
+"#);
+        assert!(ended_early_is_ok(run_steps(&ctx, &flow, &mut state)).is_ok());
+        assert_eq!(memory.get_saved_messages(), vec!["a", "b", "finally"]);
+
+        let err = ended_early_is_ok(run_steps(&ctx, &steps("- break"), &mut state)).unwrap_err();
+        assert_eq!(err.to_string(), "break step used outside of a loop");
+    }
+
+    #[test]
+    fn test_step_timeouts() {
+        let (ctx, memory) = context("step_timeouts");
+        let started = Instant::now();
+        let err = run_steps(&ctx, &steps("- sleep:\n    duration: 2s\n    timeout: 200ms"), &mut State::default()).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(err.to_string(), "step sleep timed out after 200ms");
+
+        let started = Instant::now();
+        let flow = steps("- notify:\n    notifier: hanging\n    message: hello\n    timeout: 200ms\n    retries: 3");
+        let err = run_steps(&ctx, &flow, &mut State::default()).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(err.to_string(), "step notify timed out after 200ms");
+
+        // the timeout covers nested steps, fails into upon_failure steps which it does not bind, or else upward
+        let flow = steps(r#"
+- try:
+    timeout: 200ms
+    steps:
+      - while:
+          condition: "true"
+          max_iterations: 100
+          delay: 50ms
+          steps:
+            - debug_state
+    catch:
+      - sleep:
+          duration: 300ms
+      - notify:
+          notifier: memory
+          message: "{{ error_step }}: {{ error }}"
+- wait_until:
+    condition: ready == 'yes'
+    interval: 50ms
+    timeout: 200ms
+    probe:
+      - set_variable:
+          output_var: ready
+          input: "no"
+    upon_failure:
+      - notify:
+          notifier: memory
+          message: "{{ error }}"
+"#);
+        run_steps(&ctx, &flow, &mut State::default()).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec![
+            "while: step try timed out after 200ms",
+            "step wait_until timed out after 200ms, condition ready == 'yes' still not met: condition is false"]);
+    }
+
+    #[test]
+    fn test_max_duration() {
+        let (ctx, memory) = context("max_duration");
+        let workflow: yw::YamlWorkflow = serde_yaml::from_str(r#"
+name: max_duration
+max_duration: 200ms
+steps:
+  - sleep:
+      duration: 2s
+  - notify:
+      notifier: memory
+      message: not reached
+upon_failure:
+  - sleep:
+      duration: 300ms
+  - notify:
+      notifier: memory
+      message: "{{ run_outcome }}: {{ error }}"
+"#).unwrap();
+        let started = Instant::now();
+        run(&ctx, &workflow).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(memory.get_saved_messages(), vec!["timeout: flow timed out after 200ms"]);
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let (ctx, memory) = context("wait_until");
+        let flow = steps(r#"
+- set_variable:
+    output_var: polls
+    input: "0"
+- wait_until:
+    condition: polls >= 3 and state == 'up'
+    interval: 10ms
+    timeout: 5s
+    probe:
+      - compute:
+          expression: polls + 1
+          output_var: polls
+      - notify:
+          notifier: memory
+          message: "poll {{ polls }}"
+      # fails on the first poll, which only means the condition does not hold yet
+      - if:
+          condition: polls == 1
+          then:
+            - abort_flow
+      - set_variable:
+          output_var: state
+          input: up
+- notify:
+    notifier: memory
+    message: "up after {{ polls }} polls"
+"#);
+        let mut state = State::default();
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec!["poll 1", "poll 2", "poll 3", "up after 3 polls"]);
+
+        let err = run_steps(&ctx, &steps("- wait_until:\n    condition: \"true\"\n    probe: []"), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "wait_until needs a timeout");
+        let flow = steps("- wait_until:\n    condition: \"false\"\n    interval: 10ms\n    timeout: 50ms\n    probe: []");
+        assert_eq!(run_steps(&ctx, &flow, &mut state).unwrap_err().to_string(),
+            "step wait_until timed out after 50ms, condition false still not met: condition is false");
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let (ctx, memory) = context("run_hooks");
+        let hooks = r#"
+finally:
+  - notify:
+      notifier: memory
+      message: "finally: {{ run_outcome }}"
+upon_failure:
+  - notify:
+      notifier: memory
+      message: "failed in {{ error_step }}"
+"#;
+        let workflow = |yaml: &str| -> yw::YamlWorkflow { serde_yaml::from_str(&format!("{}{}", yaml, hooks)).unwrap() };
+
+        run(&ctx, &workflow("steps:\n  - debug_state\nupon_success:\n  - notify:\n      notifier: memory\n      message: ok\n")).unwrap();
+        assert!(run(&ctx, &workflow("steps:\n  - abort_flow\n")).is_ok());
+        run(&ctx, &workflow("steps:\n  - end_flow\n  - abort_flow\n")).unwrap();
+        // a failing upon_success step fails the run, as finally steps see it
+        let err = run(&ctx, &workflow("steps:\n  - debug_state\nupon_success:\n  - abort_flow\n")).unwrap_err();
+        assert_eq!(err.to_string(), "Flow aborted");
+        assert_eq!(memory.get_saved_messages(), vec![
+            "ok", "finally: success",
+            "failed in abort_flow", "finally: failure",
+            "finally: success",
+            "finally: failure"]);
+    }
+
+    #[test]
+    fn test_on_change_failed_branch() {
+        let (ctx, memory) = context("on_change_failed_branch");
+        let flow = |notifier: &str| steps(&format!(r#"
+- set_variable:
+    output_var: status
+    input: down
+- on_change:
+    input_var: status
+    trigger_on_first_run: true
+    if_changed:
+      - notify:
+          notifier: {}
+          message: "status is {{{{ status }}}}"
+"#, notifier));
+        // the alert could not be sent, so the change is still there on the next run
+        assert!(run_steps(&ctx, &flow("offline"), &mut State::default()).is_err());
+        ctx.store.commit().unwrap();
+        assert_eq!(ctx.store.previous("status"), None);
+        run_steps(&ctx, &flow("memory"), &mut State::default()).unwrap();
+        ctx.store.commit().unwrap();
+        run_steps(&ctx, &flow("memory"), &mut State::default()).unwrap();
+        assert_eq!(memory.get_saved_messages(), vec!["status is down"]);
+    }
+
+    #[test]
+    fn test_diff_with_previous_run() {
+        let (ctx, _) = context("diff_with_previous_run");
+        let flow = steps(r#"
+- diff:
+    input_var: config
+    mode: words
+    output_var: changes
+    added_var: added
+    removed_var: removed
+"#);
+        let mut state = State::default();
+        state.vars.insert("config".to_string(), "port 22".to_string());
+        // nothing to compare with on the first run
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!((state.vars["changes"].as_str(), state.vars["added"].as_str()), ("port 22", "0"));
+        ctx.store.commit().unwrap();
+
+        state.vars.insert("config".to_string(), "port 2222".to_string());
+        run_steps(&ctx, &flow, &mut state).unwrap();
+        assert_eq!(state.vars["changes"], "port [-22-]{+2222+}");
+        assert_eq!((state.vars["added"].as_str(), state.vars["removed"].as_str()), ("1", "1"));
+    }
+
+    #[test]
+    fn test_if() {
+        let (ctx, memory) = context("if");
+        let flow = steps(r#"
+- if:
+    condition: disk > 90 and not maintenance == 'yes'
+    then:
+      - notify:
+          notifier: memory
+          message: "disk at {{ disk }}%"
+    else:
+      - notify:
+          notifier: memory
+          message: "disk fine at {{ disk }}%"
+- if:
+    condition: disk > 99
+    then:
+      - abort_flow
+"#);
+        let mut state = State::default();
+        state.vars.insert("maintenance".to_string(), "no".to_string());
+        for disk in ["95", "40"] {
+            state.vars.insert("disk".to_string(), disk.to_string());
+            run_steps(&ctx, &flow, &mut state).unwrap();
+        }
+        assert_eq!(memory.get_saved_messages(), vec!["disk at 95%", "disk fine at 40%"]);
+        state.vars.remove("disk");
+        assert!(run_steps(&ctx, &flow, &mut state).is_err());
+    }
+}
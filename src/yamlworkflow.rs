@@ -1,9 +1,11 @@
 #![allow(dead_code)]
-use std::{io::BufReader, fs::File, collections::HashMap, time::Duration};
+use std::{io::BufReader, fs::File, collections::{HashMap, BTreeMap}, time::Duration};
 
 use serde::Deserialize;
 use anyhow::{anyhow, Result};
 
+use crate::retry;
+
 
 #[derive(Debug, Deserialize )]
 pub struct Trigger {
@@ -30,7 +32,9 @@ pub enum Step {
         url: String,
         output_var: Option<String>,
         #[serde(flatten)]
-        fail_spec: FailSpec
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     PostUrl {
         url: String,
@@ -39,6 +43,8 @@ pub enum Step {
         headers: HashMap<String,String>,
         #[serde(flatten)]
         fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     TextExtractOneCapture {
         input_var: Option<String>,
@@ -46,57 +52,683 @@ pub enum Step {
         regex: String,
         #[serde(flatten)]
         fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    TextExtract {
+        input_var: Option<String>,
+        /// named capture groups are saved to variables of the same name
+        regex: String,
+        /// first capture group, or whole match if the regex has no group
+        output_var: Option<String>,
+        /// collect every match as a JSON list instead of only the first one
+        #[serde(default)]
+        all_matches: bool,
+        /// variable receiving the number of matches
+        count_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Transform {
+        input_var: Option<String>,
+        output_var: Option<String>,
+        /// applied in order, each one to the result of the previous one
+        operations: Vec<TextOperation>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    JsonExtract {
+        input_var: Option<String>,
+        path: Option<String>,
+        output_var: Option<String>,
+        /// several JSON paths at once, keyed by output variable name
+        #[serde(default)]
+        paths: BTreeMap<String,String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    PrometheusExtract {
+        input_var: Option<String>,
+        /// PromQL-like series selector, e.g. `up{job="node"}`
+        selector: String,
+        /// sum, avg, min, max or count, needed when several series match
+        aggregate: Option<String>,
+        output_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    HtmlExtract {
+        input_var: Option<String>,
+        /// CSS selector
+        selector: String,
+        /// output this attribute of the elements instead of their text
+        attribute: Option<String>,
+        output_var: Option<String>,
+        /// collect every matching element as a JSON list instead of only the first one
+        #[serde(default)]
+        all_matches: bool,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    XmlExtract {
+        input_var: Option<String>,
+        xpath: String,
+        /// prefixes usable in the XPath, mapped to namespace URIs
+        #[serde(default)]
+        namespaces: BTreeMap<String,String>,
+        output_var: Option<String>,
+        #[serde(default)]
+        all_matches: bool,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    CsvExtract {
+        /// the CSV text is read from `path` if given, from the input variable otherwise
+        input_var: Option<String>,
+        path: Option<String>,
+        #[serde(default = "default_csv_delimiter")]
+        delimiter: char,
+        /// detected from the first row when not set
+        has_headers: Option<bool>,
+        /// conditions that rows must all meet to be kept
+        #[serde(default)]
+        filter: Vec<RowFilter>,
+        /// columns kept in the output, by header name or position; all of them if empty
+        #[serde(default)]
+        columns: Vec<String>,
+        /// receives the remaining rows as CSV
+        output_var: Option<String>,
+        /// values computed over the remaining rows, keyed by output variable name
+        #[serde(default)]
+        aggregates: BTreeMap<String, CsvAggregate>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Compute {
+        /// arithmetic and logical expression over variables, e.g. `round(used / total * 100, 1)`
+        expression: String,
+        output_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Datetime {
+        /// now, parse, diff, before or after
+        operation: String,
+        input_var: Option<String>,
+        /// compared with the input by diff, before and after; the current time if not set
+        other_var: Option<String>,
+        output_var: Option<String>,
+        #[serde(flatten)]
+        options: DatetimeOptions,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Encode {
+        input_var: Option<String>,
+        /// md5, sha1, sha256, hmac_sha1, hmac_sha256, base64(_decode), base64url(_decode),
+        /// hex(_decode), url_encode, url_decode or html_escape
+        operation: String,
+        /// key for HMAC operations
+        secret: Option<String>,
+        /// hex (default) or base64, for digests and HMACs
+        digest_format: Option<String>,
+        output_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     CompareVar {
         input_var: Option<String>,
+        /// may refer to other variables as `{{ var_name }}`; not needed for is_empty
+        #[serde(default)]
         compare_with: String,
+        /// see `compare::compare` for the supported comparison types
         compare_for: String,
         #[serde(default)]
         if_true: Vec<Step>,
         #[serde(default)]
         if_false: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Switch {
+        input_var: Option<String>,
+        /// checked in order, the steps of the first matching case are run
+        cases: Vec<SwitchCase>,
+        /// run when no case matches
+        #[serde(default)]
+        default: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    If {
+        /// e.g. `disk > 90 and not maintenance == 'yes'`, see `expr` for the syntax
+        condition: String,
+        #[serde(default)]
+        then: Vec<Step>,
+        #[serde(default)]
+        #[serde(rename = "else")]
+        otherwise: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Try {
+        steps: Vec<Step>,
+        /// run when one of the steps fails, with the `error` and `error_step` variables set
+        #[serde(default)]
+        catch: Vec<Step>,
+        /// run in any case, after `steps` and `catch`
+        #[serde(default)]
+        finally: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    ForEach {
+        /// the list to iterate on, unless `items` is given
+        input_var: Option<String>,
+        /// how to read the input: json, lines, comma or regex; JSON lists or lines if not set
+        format: Option<String>,
+        /// iterate on all matches of this regex in the input
+        regex: Option<String>,
+        /// a literal list to iterate on
+        items: Option<Vec<String>>,
+        #[serde(default = "default_item_var")]
+        item_var: String,
+        #[serde(default = "default_index_var")]
+        index_var: String,
+        steps: Vec<Step>,
+        /// keep going with the next items when steps fail for one, and handle failures after the loop
+        #[serde(default)]
+        continue_on_error: bool,
+        /// receives the failed items as a JSON list
+        failures_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    /// runs steps, then stops once the condition is true
+    RepeatUntil {
+        #[serde(flatten)]
+        spec: LoopSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    /// runs the probe steps every `interval` until the condition is true, failing after `timeout`
+    WaitUntil {
+        condition: String,
+        probe: Vec<Step>,
+        #[serde(deserialize_with = "duration_parser")]
+        #[serde(default = "default_poll_interval")]
+        interval: Duration,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    /// runs steps as long as the condition is true, checking it first
+    While {
+        #[serde(flatten)]
+        spec: LoopSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Parallel {
+        branches: Vec<Branch>,
+        /// all (default), any or first_success: which branches must succeed for the block to succeed
+        #[serde(default = "default_join")]
+        join: String,
+        /// how variables set by branches come back: all (default, in branch order), prefixed
+        /// (as `<branch name>_<variable>`) or none
+        #[serde(default = "default_merge")]
+        merge: String,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Retry {
+        steps: Vec<Step>,
+        #[serde(flatten)]
+        policy: RetryPolicy,
+        /// run once all attempts failed
+        #[serde(default)]
+        upon_failure: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Call {
+        function: String,
+        /// values of the function's parameters, which may refer to variables as `{{ var_name }}`
+        #[serde(default)]
+        params: BTreeMap<String,String>,
+        /// receives the last output of the function
+        output_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     Notify {
         notifier: String,
         message: String,
         #[serde(flatten)]
         fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     AbortFlow,
+    /// stops the flow early, as a success
+    EndFlow,
+    /// stops the innermost loop
+    Break,
+    /// goes on with the next iteration of the innermost loop
+    Continue,
     DebugState,
     Sleep {
         #[serde(deserialize_with = "duration_parser")]
-        duration: Duration
+        duration: Duration,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    OnChange {
+        /// compared with its value at the end of the previous run
+        input_var: String,
+        /// name under which the value is kept between runs, e.g. `status_{{ item }}` in a loop; the variable name by default
+        key: Option<String>,
+        /// receives the previous value, empty on the first run
+        previous_var: Option<String>,
+        /// whether the first run, without previous value, counts as a change
+        #[serde(default)]
+        trigger_on_first_run: bool,
+        #[serde(default)]
+        if_changed: Vec<Step>,
+        #[serde(default)]
+        if_unchanged: Vec<Step>,
+        #[serde(flatten)]
+        timeout: Timeout,
+    },
+    Diff {
+        /// the new text
+        input_var: Option<String>,
+        /// the old text; if not set, the input is compared with its value from the previous run
+        other_var: Option<String>,
+        /// name under which the value is kept between runs, which can use placeholders; the input variable name by default
+        key: Option<String>,
+        /// unified (default) or words
+        #[serde(default = "default_diff_mode")]
+        mode: String,
+        /// lines of context around changes in unified diffs
+        #[serde(default = "default_diff_context")]
+        context_lines: usize,
+        output_var: Option<String>,
+        /// receive the numbers of added and removed lines
+        added_var: Option<String>,
+        removed_var: Option<String>,
+        #[serde(flatten)]
+        fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     ReadFromFile {
         path: String,
         output_var: Option<String>,
         #[serde(flatten)]
         fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     AppendToFile {
         path: String,
         input_var: Option<String>,
         #[serde(flatten)]
         fail_spec: FailSpec,
+        #[serde(flatten)]
+        timeout: Timeout,
     },
     SetVariable {
         output_var: Option<String>,
-        input: String
+        input: String,
+        #[serde(flatten)]
+        timeout: Timeout,
+    }
+}
+
+impl Step {
+    /// The name of the step type, as written in flow files
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Step::GetUrl { .. } => "get_url",
+            Step::PostUrl { .. } => "post_url",
+            Step::TextExtractOneCapture { .. } => "text_extract_one_capture",
+            Step::TextExtract { .. } => "text_extract",
+            Step::Transform { .. } => "transform",
+            Step::JsonExtract { .. } => "json_extract",
+            Step::PrometheusExtract { .. } => "prometheus_extract",
+            Step::HtmlExtract { .. } => "html_extract",
+            Step::XmlExtract { .. } => "xml_extract",
+            Step::CsvExtract { .. } => "csv_extract",
+            Step::Compute { .. } => "compute",
+            Step::Datetime { .. } => "datetime",
+            Step::Encode { .. } => "encode",
+            Step::CompareVar { .. } => "compare_var",
+            Step::Switch { .. } => "switch",
+            Step::If { .. } => "if",
+            Step::Try { .. } => "try",
+            Step::ForEach { .. } => "for_each",
+            Step::RepeatUntil { .. } => "repeat_until",
+            Step::WaitUntil { .. } => "wait_until",
+            Step::While { .. } => "while",
+            Step::Parallel { .. } => "parallel",
+            Step::Retry { .. } => "retry",
+            Step::Call { .. } => "call",
+            Step::Notify { .. } => "notify",
+            Step::AbortFlow => "abort_flow",
+            Step::EndFlow => "end_flow",
+            Step::Break => "break",
+            Step::Continue => "continue",
+            Step::DebugState => "debug_state",
+            Step::Sleep { .. } => "sleep",
+            Step::OnChange { .. } => "on_change",
+            Step::Diff { .. } => "diff",
+            Step::ReadFromFile { .. } => "read_from_file",
+            Step::AppendToFile { .. } => "append_to_file",
+            Step::SetVariable { .. } => "set_variable",
+        }
+    }
+
+    /// How long the step may run, retries and nested steps included, before it fails
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            Step::GetUrl { timeout, .. } | Step::PostUrl { timeout, .. } |
+                Step::TextExtractOneCapture { timeout, .. } | Step::TextExtract { timeout, .. } |
+                Step::Transform { timeout, .. } | Step::JsonExtract { timeout, .. } |
+                Step::PrometheusExtract { timeout, .. } | Step::HtmlExtract { timeout, .. } |
+                Step::XmlExtract { timeout, .. } | Step::CsvExtract { timeout, .. } | Step::Compute { timeout, .. } |
+                Step::Datetime { timeout, .. } | Step::Encode { timeout, .. } | Step::CompareVar { timeout, .. } |
+                Step::Switch { timeout, .. } | Step::If { timeout, .. } | Step::Try { timeout, .. } |
+                Step::ForEach { timeout, .. } | Step::RepeatUntil { timeout, .. } | Step::WaitUntil { timeout, .. } |
+                Step::While { timeout, .. } | Step::Parallel { timeout, .. } | Step::Retry { timeout, .. } |
+                Step::Call { timeout, .. } | Step::Notify { timeout, .. } | Step::Sleep { timeout, .. } |
+                Step::OnChange { timeout, .. } | Step::Diff { timeout, .. } | Step::ReadFromFile { timeout, .. } |
+                Step::AppendToFile { timeout, .. } | Step::SetVariable { timeout, .. } => timeout.duration,
+            Step::AbortFlow | Step::EndFlow | Step::Break | Step::Continue | Step::DebugState => None,
+        }
     }
 }
 
 #[derive(Debug, Deserialize, Clone )]
+#[serde(rename_all ="snake_case")]
+pub enum TextOperation {
+    Trim,
+    TrimStart,
+    TrimEnd,
+    Uppercase,
+    Lowercase,
+    Replace {
+        regex: String,
+        /// may refer to capture groups as $1, $name...
+        #[serde(default)]
+        with: String,
+    },
+    /// splits into a JSON list
+    Split(String),
+    /// joins a JSON list
+    Join(String),
+    Substring {
+        #[serde(default)]
+        start: usize,
+        length: Option<usize>,
+    },
+    Pad {
+        width: usize,
+        #[serde(default = "default_pad_char")]
+        with: char,
+        #[serde(default)]
+        side: PadSide,
+    },
+    FilterLines {
+        regex: String,
+        /// keep the lines that don't match instead
+        #[serde(default)]
+        invert: bool,
+    },
+    Truncate {
+        length: usize,
+        /// appended when the text is cut, counted in the length
+        #[serde(default)]
+        ellipsis: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq )]
+#[serde(rename_all ="snake_case")]
+pub enum PadSide {
+    #[default]
+    Left,
+    Right,
+}
+
+fn default_pad_char() -> char { ' ' }
+
+/// A named list of steps that can be called from several places of a flow
+#[derive(Debug, Deserialize, Clone )]
+pub struct Function {
+    /// names of the parameters, which the steps see as variables
+    #[serde(default)]
+    pub params: Vec<String>,
+    pub steps: Vec<Step>,
+    /// variables copied back to the caller once the steps are done
+    #[serde(default)]
+    pub returns: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct Branch {
+    /// `branch1`, `branch2`... by default
+    pub name: Option<String>,
+    pub steps: Vec<Step>,
+    #[serde(default)]
+    pub upon_failure: Vec<Step>,
+}
+
+fn default_join() -> String { "all".to_string() }
+
+fn default_merge() -> String { "all".to_string() }
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct LoopSpec {
+    /// expression over variables, see `expr`
+    pub condition: String,
+    pub steps: Vec<Step>,
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: u32,
+    /// pause between iterations
+    #[serde(deserialize_with = "optional_duration_parser")]
+    #[serde(default)]
+    pub delay: Option<Duration>,
+    /// receives the iteration number, starting at 1
+    pub iteration_var: Option<String>,
+    /// run when max_iterations is reached; without it, reaching it is a failure
+    #[serde(default)]
+    pub on_exhausted: Vec<Step>,
+}
+
+fn default_max_iterations() -> u32 { 10 }
+
+fn default_poll_interval() -> Duration { Duration::new(5,0) }
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct SwitchCase {
+    /// equality unless set, see `compare::compare` for the supported comparison types
+    #[serde(default = "default_compare_for")]
+    pub compare_for: String,
+    /// may refer to other variables as `{{ var_name }}`
+    #[serde(default)]
+    pub compare_with: String,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+fn default_compare_for() -> String { "equality".to_string() }
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct RowFilter {
+    pub column: String,
+    pub compare_for: String,
+    pub compare_with: String,
+}
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct CsvAggregate {
+    pub column: Option<String>,
+    /// count, sum, avg, min, max, first, last or list
+    pub aggregate: String,
+}
+
+fn default_csv_delimiter() -> char { ',' }
+
+fn default_item_var() -> String { "item".to_string() }
+
+fn default_index_var() -> String { "index".to_string() }
+
+fn default_diff_mode() -> String { "unified".to_string() }
+
+fn default_diff_context() -> usize { 3 }
+
+#[derive(Debug, Deserialize, Clone )]
+pub struct DatetimeOptions {
+    /// strftime-like format of the input dates, or `unix`; usual formats are detected if not set
+    pub input_format: Option<String>,
+    /// strftime-like format of the output, or `unix`; RFC 3339 if not set
+    pub format: Option<String>,
+    /// e.g. Europe/Paris; local time if not set
+    pub timezone: Option<String>,
+    /// added to the time before output or comparison, e.g. `26h` or `-15m`
+    pub offset: Option<String>,
+    /// seconds, minutes, hours or days, for diff
+    pub unit: Option<String>,
+}
+
+/// How long a step may run, retries and nested steps included, before it fails
+#[derive(Debug, Deserialize, Clone )]
+pub struct Timeout {
+    #[serde(rename = "timeout")]
+    #[serde(deserialize_with = "optional_duration_parser")]
+    #[serde(default)]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize, Clone )]
+#[serde(try_from = "FailSpecFields")]
 pub struct FailSpec {
+    /// shorthand for a `retry` policy retrying any error at a fixed interval
     pub retries: Option<u8>,
+    pub retry_interval: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
+    pub upon_failure: Vec<Step>,
+}
+
+/// A `FailSpec` as written in a flow, before checking that its settings go together
+#[derive(Deserialize)]
+struct FailSpecFields {
+    retries: Option<u8>,
     #[serde(deserialize_with = "optional_duration_parser")]
     #[serde(default)]
-    pub retry_interval: Option<Duration>,
+    retry_interval: Option<Duration>,
+    retry: Option<RetryPolicy>,
     #[serde(default)]
     #[serde(with = "serde_yaml::with::singleton_map_recursive")]
-    pub upon_failure: Vec<Step>,
+    upon_failure: Vec<Step>,
+}
+
+impl TryFrom<FailSpecFields> for FailSpec {
+    type Error = String;
+
+    fn try_from(fields: FailSpecFields) -> Result<Self, String> {
+        if fields.retry.is_some() && (fields.retries.is_some() || fields.retry_interval.is_some()) {
+            return Err("retries and retry_interval cannot be combined with a retry policy, use its max_attempts and interval".to_string());
+        }
+        let FailSpecFields { retries, retry_interval, retry, upon_failure } = fields;
+        Ok(Self { retries, retry_interval, retry, upon_failure })
+    }
+}
+
+impl FailSpec {
+    /// The retry policy of the step, a single attempt if none is set
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match (&self.retry, self.retries) {
+            (Some(policy), _) => policy.clone(),
+            (None, Some(retries)) => RetryPolicy::fixed(retries, self.retry_interval.unwrap_or(Duration::new(1,0))),
+            (None, None) => RetryPolicy::fixed(0, Duration::ZERO),
+        }
+    }
 }
 
+/// How failing operations are attempted again, see `retry::retry`
+#[derive(Debug, Deserialize, Clone )]
+pub struct RetryPolicy {
+    /// attempts in total, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// no new attempt is made once this much time has passed since the first one
+    #[serde(deserialize_with = "optional_duration_parser")]
+    #[serde(default)]
+    pub max_total_time: Option<Duration>,
+    /// wait before the first retry
+    #[serde(deserialize_with = "duration_parser")]
+    #[serde(default = "default_retry_interval")]
+    pub interval: Duration,
+    /// the wait is multiplied by this after each retry
+    #[serde(deserialize_with = "backoff_parser")]
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    /// longest wait between two attempts
+    #[serde(deserialize_with = "optional_duration_parser")]
+    #[serde(default)]
+    pub max_interval: Option<Duration>,
+    /// share of each wait that is random, from 0 (none) to 1 (anywhere between nothing and the full wait)
+    #[serde(deserialize_with = "jitter_parser")]
+    #[serde(default)]
+    pub jitter: f64,
+    /// kinds of errors worth retrying: http_5xx, http_4xx, timeout, connection or io; any error if empty
+    #[serde(deserialize_with = "retry_on_parser")]
+    #[serde(default)]
+    pub retry_on: Vec<String>,
+}
+
+impl RetryPolicy {
+    /// Retries any error a number of times, always waiting the same
+    pub fn fixed(retries: u8, interval: Duration) -> Self {
+        Self { max_attempts: retries as u32 + 1, max_total_time: None, interval, backoff: 1.0, max_interval: None, jitter: 0.0, retry_on: vec![] }
+    }
+}
+
+fn default_max_attempts() -> u32 { 3 }
+
+fn default_retry_interval() -> Duration { Duration::new(1,0) }
+
+fn default_backoff() -> f64 { 1.0 }
+
 #[derive(Debug, Deserialize )]
 pub struct YamlWorkflow {
    #[serde(rename = "name")]
@@ -109,6 +741,27 @@ pub struct YamlWorkflow {
     #[serde(default)]
     #[serde(with = "serde_yaml::with::singleton_map_recursive")]
     pub upon_failure: Vec<Step>,
+    /// run after the main steps when they succeed
+    #[serde(default)]
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub upon_success: Vec<Step>,
+    /// run at the end of every run, whatever its outcome
+    #[serde(default)]
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub finally: Vec<Step>,
+    /// step lists callable from `call` steps, by name
+    #[serde(default)]
+    #[serde(alias = "subflows")]
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub functions: HashMap<String, Function>,
+    /// file in which the cookie jar of HTTP steps is kept between runs
+    pub cookie_file: Option<String>,
+    /// file in which values are kept between runs, `<flow name>.state.json` by default
+    pub state_file: Option<String>,
+    /// a run is aborted once it has run this long
+    #[serde(deserialize_with = "optional_duration_parser")]
+    #[serde(default)]
+    pub max_duration: Option<Duration>,
 }
 
 impl YamlWorkflow {
@@ -129,6 +782,33 @@ where D: serde::Deserializer<'de> {
     Ok(Some(parse_duration::parse(&buf).map_err(serde::de::Error::custom)?))
 }
 
+fn retry_on_parser<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where D: serde::Deserializer<'de> {
+    let kinds = Vec::<String>::deserialize(deserializer)?;
+    match kinds.iter().find(|kind| !retry::is_kind(kind)) {
+        Some(unknown) => Err(serde::de::Error::custom(format!("unknown error kind to retry on: {}", unknown))),
+        None => Ok(kinds)
+    }
+}
+
+fn backoff_parser<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let backoff = f64::deserialize(deserializer)?;
+    if !(backoff.is_finite() && backoff > 0.0) {
+        return Err(serde::de::Error::custom(format!("backoff must be a positive number, not {}", backoff)));
+    }
+    Ok(backoff)
+}
+
+fn jitter_parser<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let jitter = f64::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&jitter) {
+        return Err(serde::de::Error::custom(format!("jitter must be between 0 and 1, not {}", jitter)));
+    }
+    Ok(jitter)
+}
+
 fn duration_parser<'de, D>(deserializer: D) -> Result<std::time::Duration, D::Error> 
 where D: serde::Deserializer<'de> {
     let buf = String::deserialize(deserializer)?;
@@ -168,3 +848,51 @@ fn test_duration_parsing() {
     if let Some(d) = tester1.duration { assert_eq!(d,Duration::new(3,0)); } else {panic!("did not parse duration correctly");}
     assert_eq!(serde_yaml::from_str::<Tester>("").unwrap().duration, None);
 }
+
+
+#[cfg(test)]
+#[test]
+fn test_retry_settings() {
+    let parse = |yaml: &str| serde_yaml::with::singleton_map_recursive::deserialize::<Vec<Step>, _>(serde_yaml::Deserializer::from_str(yaml));
+    let max_attempts = |yaml: &str| match &parse(yaml).unwrap()[0] {
+        Step::GetUrl { fail_spec, .. } => fail_spec.retry_policy().max_attempts,
+        _ => unreachable!()
+    };
+    assert_eq!(max_attempts("- get_url:\n    url: http://localhost\n    retry:\n      max_attempts: 5\n      retry_on: [http_5xx, timeout]"), 5);
+    assert_eq!(max_attempts("- get_url:\n    url: http://localhost\n    retries: 2"), 3);
+
+    let err = parse("- get_url:\n    url: http://localhost\n    retries: 2\n    retry:\n      max_attempts: 5").unwrap_err();
+    assert!(err.to_string().contains("cannot be combined with a retry policy"));
+    let err = parse("- retry:\n    retry_on: [http_6xx]\n    steps:\n      - abort_flow").unwrap_err();
+    assert!(err.to_string().contains("unknown error kind to retry on: http_6xx"));
+    for (setting, message) in [("jitter: .nan", "jitter must be"), ("jitter: 1.5", "jitter must be"), ("jitter: -0.1", "jitter must be"),
+        ("backoff: 0", "backoff must be"), ("backoff: -2", "backoff must be")] {
+        let err = parse(&format!("- retry:\n    {}\n    steps:\n      - abort_flow", setting)).unwrap_err();
+        assert!(err.to_string().contains(message), "{}: {}", setting, err);
+    }
+    assert!(parse("- retry:\n    jitter: 1\n    backoff: 1.5\n    steps:\n      - abort_flow").is_ok());
+}
+
+
+#[cfg(test)]
+#[test]
+fn test_step_timeouts() {
+    let parse = |yaml: &str| serde_yaml::with::singleton_map_recursive::deserialize::<Vec<Step>, _>(serde_yaml::Deserializer::from_str(yaml)).unwrap();
+    let steps = parse(r#"
+- sleep:
+    duration: 2s
+    timeout: 200ms
+- while:
+    condition: "true"
+    timeout: 1m
+    steps:
+      - debug_state
+- get_url:
+    url: http://localhost
+    timeout: 5s
+- debug_state
+"#);
+    let timeouts: Vec<Option<Duration>> = steps.iter().map(Step::timeout).collect();
+    assert_eq!(timeouts, vec![Some(Duration::from_millis(200)), Some(Duration::from_secs(60)), Some(Duration::from_secs(5)), None]);
+}
+